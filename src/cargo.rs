@@ -8,6 +8,7 @@ use toml::{Value, map::Map};
 use cli::Args;
 use errors::*;
 use extensions::CommandExt;
+use rustc::Target;
 use util;
 use sysroot::XargoMode;
 use xargo::Home;
@@ -18,28 +19,44 @@ pub struct Rustflags {
 }
 
 impl Rustflags {
-    pub fn hash<H>(&self, hasher: &mut H)
-    where
-        H: Hasher,
-    {
+    /// The flags that affect the compilation of the sysroot, i.e. all of them
+    /// but the linker arguments
+    pub fn sysroot_flags(&self) -> Vec<&str> {
+        let mut sysroot_flags = vec![];
         let mut flags = self.flags.iter();
 
         while let Some(flag) = flags.next() {
             if flag == "-C" {
                 if let Some(next) = flags.next() {
                     if next.starts_with("link-arg=") || next.starts_with("link-args=") {
-                        // don't hash linker arguments
+                        // skip linker arguments
                     } else {
-                        flag.hash(hasher);
-                        next.hash(hasher);
+                        sysroot_flags.push(&**flag);
+                        sysroot_flags.push(&**next);
                     }
                 } else {
-                    flag.hash(hasher);
+                    sysroot_flags.push(&**flag);
                 }
             } else {
-                flag.hash(hasher);
+                sysroot_flags.push(&**flag);
             }
         }
+
+        sysroot_flags
+    }
+
+    pub fn hash<H>(&self, hasher: &mut H)
+    where
+        H: Hasher,
+    {
+        for flag in self.sysroot_flags() {
+            flag.hash(hasher);
+        }
+    }
+
+    /// Whether the sysroot would be compiled with no extra flags
+    pub fn is_empty(&self) -> bool {
+        self.sysroot_flags().is_empty()
     }
 
     pub fn push(&mut self, flags: &[&str]) {
@@ -158,6 +175,29 @@ pub fn command() -> Command {
         .unwrap_or_else(|| Command::new("cargo"))
 }
 
+/// `cargo` of the toolchain whose sysroot is `sysroot`
+///
+/// This doesn't depend on the current directory, unlike the toolchain rustup
+/// would pick from a `rust-toolchain` file. The `CARGO` and `RUSTC` env
+/// variables still take precedence.
+pub fn toolchain_command(sysroot: &Path) -> Command {
+    let bin = sysroot.join("bin");
+    let cargo = bin.join(format!("cargo{}", env::consts::EXE_SUFFIX));
+    let rustc = bin.join(format!("rustc{}", env::consts::EXE_SUFFIX));
+
+    let mut cmd = if env::var_os("CARGO").is_none() && cargo.is_file() {
+        Command::new(cargo)
+    } else {
+        command()
+    };
+
+    if env::var_os("RUSTC").is_none() && rustc.is_file() {
+        cmd.env("RUSTC", rustc);
+    }
+
+    cmd
+}
+
 pub fn run(args: &Args, verbose: bool) -> Result<ExitStatus> {
     command()
         .args(args.all())
@@ -177,17 +217,176 @@ impl Config {
             Ok(None)
         }
     }
+
+    /// Returns the top-level `section` of the configuration, e.g. `source`
+    pub fn section(&self, section: &str) -> Option<&Value> {
+        self.table.get(section)
+    }
+
+    /// Returns the expansion of the `alias.{}` entry named `name`, falling back
+    /// to Cargo's builtin aliases
+    pub fn alias(&self, name: &str) -> Result<Option<Vec<String>>> {
+        if let Some(v) = self.table.get("alias").and_then(|t| t.get(name)) {
+            let error = || format!(".cargo/config: alias.{} must be a string or an array of strings", name);
+
+            if let Some(s) = v.as_str() {
+                Ok(Some(s.split_whitespace().map(|w| w.to_owned()).collect()))
+            } else if let Some(array) = v.as_array() {
+                let mut expansion = vec![];
+                for value in array {
+                    expansion.push(value.as_str().ok_or_else(error)?.to_owned());
+                }
+                Ok(Some(expansion))
+            } else {
+                Err(error())?
+            }
+        } else {
+            Ok(Config::builtin_alias(name))
+        }
+    }
+
+    /// Returns the expansion of Cargo's builtin alias `name`
+    pub fn builtin_alias(name: &str) -> Option<Vec<String>> {
+        let expansion = match name {
+            "b" => "build",
+            "c" => "check",
+            "d" => "doc",
+            "r" => "run",
+            "rm" => "remove",
+            "t" => "test",
+            _ => return None,
+        };
+
+        Some(vec![expansion.to_owned()])
+    }
 }
 
-pub fn config() -> Result<Option<Config>> {
-    let cd = env::current_dir().chain_err(|| "couldn't get the current directory")?;
+/// Merges `other` into `base`, with `base` taking precedence
+///
+/// Tables are merged recursively and arrays are concatenated, like Cargo does
+fn merge(base: &mut Value, other: Value) {
+    match (base, other) {
+        (&mut Value::Table(ref mut base), Value::Table(other)) => {
+            for (k, v) in other {
+                match base.get_mut(&k) {
+                    Some(b) => merge(b, v),
+                    None => {
+                        base.insert(k, v);
+                    }
+                }
+            }
+        }
+        (&mut Value::Array(ref mut base), Value::Array(mut other)) => {
+            other.append(base);
+            *base = other;
+        }
+        _ => {}
+    }
+}
 
-    if let Some(p) = util::search(&cd, ".cargo/config") {
-        Ok(Some(Config {
-            table: util::parse(&p.join(".cargo/config"))?,
-        }))
-    } else {
-        Ok(None)
+/// Returns the `.cargo/config` file in `dir`, if any
+fn config_file(dir: &Path) -> Option<PathBuf> {
+    // Like Cargo, prefer the file without the extension if both exist
+    [".cargo/config", ".cargo/config.toml"]
+        .iter()
+        .map(|f| dir.join(f))
+        .find(|p| p.is_file())
+}
+
+/// Returns `$CARGO_HOME`
+pub fn home() -> Option<PathBuf> {
+    env::var_os("CARGO_HOME")
+        .map(PathBuf::from)
+        .or_else(|| dirs::home_dir().map(|h| h.join(".cargo")))
+}
+
+/// Returns the merged Cargo configuration
+///
+/// Like Cargo, this looks for `.cargo/config{,.toml}` in `cd` and all its
+/// parent directories, and then in `$CARGO_HOME`. Files closer to `cd` take
+/// precedence.
+pub fn config(cd: &Path) -> Result<Option<Config>> {
+    let mut files = vec![];
+    let mut dir = Some(cd);
+    while let Some(d) = dir {
+        files.extend(config_file(d));
+        dir = d.parent();
+    }
+
+    if let Some(home) = home() {
+        for f in [home.join("config"), home.join("config.toml")].iter() {
+            if f.is_file() {
+                if !files.iter().any(|p| same_file(p, f)) {
+                    files.push(f.clone());
+                }
+                break;
+            }
+        }
+    }
+
+    let mut table: Option<Value> = None;
+    for f in files {
+        let mut value = util::parse(&f)?;
+        // Like Cargo, paths are relative to the directory containing `.cargo`
+        if let Some(base) = f.parent().and_then(Path::parent) {
+            absolute_paths(&mut value, base);
+        }
+        match table {
+            Some(ref mut table) => merge(table, value),
+            None => table = Some(value),
+        }
+    }
+
+    Ok(table.map(|table| Config { table }))
+}
+
+/// Makes the paths in `[source]`, `[patch]`, `http.cainfo` and `build.target`
+/// absolute so that they can be used from another directory
+fn absolute_paths(value: &mut Value, base: &Path) {
+    let make_absolute = |v: &mut Value| {
+        if let Value::String(ref mut s) = *v {
+            if Path::new(s.as_str()).is_relative() {
+                *s = base.join(s.as_str()).display().to_string();
+            }
+        }
+    };
+
+    if let Some(sources) = value.get_mut("source").and_then(|s| s.as_table_mut()) {
+        for (_, source) in sources.iter_mut() {
+            for key in &["directory", "local-registry"] {
+                if let Some(v) = source.get_mut(*key) {
+                    make_absolute(v);
+                }
+            }
+        }
+    }
+
+    if let Some(v) = value.get_mut("http").and_then(|h| h.get_mut("cainfo")) {
+        make_absolute(v);
+    }
+
+    // Only a path to a target specification file, not a triple
+    if let Some(v) = value.get_mut("build").and_then(|b| b.get_mut("target")) {
+        if v.as_str().map(Target::is_path).unwrap_or(false) {
+            make_absolute(v);
+        }
+    }
+
+    if let Some(patches) = value.get_mut("patch").and_then(|p| p.as_table_mut()) {
+        for patch in patches.iter_mut().filter_map(|(_, p)| p.as_table_mut()) {
+            for (_, dep) in patch.iter_mut() {
+                if let Some(v) = dep.get_mut("path") {
+                    make_absolute(v);
+                }
+            }
+        }
+    }
+}
+
+fn same_file(a: &Path, b: &Path) -> bool {
+    match (a.canonicalize(), b.canonicalize()) {
+        (Ok(a), Ok(b)) => a == b,
+        _ => a == b,
     }
 }
 
@@ -200,20 +399,31 @@ impl<'t> Profile<'t> {
     where
         H: Hasher,
     {
+        // don't hash an empty map
+        if self.is_empty() {
+            return;
+        }
+
+        self.sysroot_settings().to_string().hash(hasher);
+    }
+
+    /// Whether this profile doesn't change how the sysroot is compiled
+    pub fn is_empty(&self) -> bool {
+        match self.sysroot_settings() {
+            Value::Table(ref table) => table.is_empty(),
+            _ => false,
+        }
+    }
+
+    fn sysroot_settings(&self) -> Value {
         let mut v = self.table.clone();
 
-        // Don't include `lto` in the hash because it doesn't affect compilation
-        // of `.rlib`s
+        // Don't include `lto` because it doesn't affect compilation of `.rlib`s
         if let Value::Table(ref mut table) = v {
             table.remove("lto");
-
-            // don't hash an empty map
-            if table.is_empty() {
-                return;
-            }
         }
 
-        v.to_string().hash(hasher);
+        v
     }
 }
 
@@ -258,7 +468,7 @@ impl Root {
     }
 }
 
-pub fn root(mode: XargoMode, manifest_path: Option<&str>) -> Result<Option<Root>> {
+pub fn root(mode: XargoMode, cd: &Path, manifest_path: Option<&str>) -> Result<Option<Root>> {
     // Don't require a 'Cargo.toml' to exist when 'xargo-check' is used
     let name = match mode {
         XargoMode::Build => "Cargo.toml",
@@ -266,9 +476,9 @@ pub fn root(mode: XargoMode, manifest_path: Option<&str>) -> Result<Option<Root>
     };
 
     let cd = match manifest_path {
-        None => env::current_dir().chain_err(|| "couldn't get the current directory")?,
+        None => cd.to_path_buf(),
         Some(p) => {
-            let mut pb = PathBuf::from(p);
+            let mut pb = cd.join(p);
             pb.pop(); // strip filename, keep directory containing Cargo.toml
             pb
         }
@@ -276,15 +486,51 @@ pub fn root(mode: XargoMode, manifest_path: Option<&str>) -> Result<Option<Root>
     Ok(util::search(&cd, name).map(|p| Root { path: p.to_owned() }))
 }
 
-#[derive(Clone, Copy, PartialEq)]
+/// Cargo subcommands
+///
+/// See `cargo --list`
+#[derive(Clone, Debug, PartialEq)]
 pub enum Subcommand {
+    Add,
+    Bench,
+    Build,
+    Check,
     Clean,
+    Config,
     Doc,
+    Fetch,
+    Fix,
+    GenerateLockfile,
+    Help,
+    Info,
     Init,
+    Install,
+    LocateProject,
+    Login,
+    Logout,
+    Metadata,
     New,
-    Other,
+    Owner,
+    Package,
+    Pkgid,
+    Publish,
+    ReadManifest,
+    Remove,
+    Report,
+    Run,
+    Rustc,
+    Rustdoc,
     Search,
+    Test,
+    Tree,
+    Uninstall,
     Update,
+    Vendor,
+    VerifyProject,
+    Version,
+    Yank,
+    /// A subcommand that's not built into Cargo, i.e. a `cargo-$name` binary
+    External(String),
 }
 
 impl Subcommand {
@@ -292,22 +538,83 @@ impl Subcommand {
         use self::Subcommand::*;
 
         match *self {
-            Clean | Init | New | Search | Update => false,
-            _ => true,
+            // These compile code for the target
+            Bench | Build | Check | Doc | Fix | Install | Package | Publish | Run | Rustc
+            | Rustdoc | Test => true,
+            // These only deal with manifests, lockfiles, registries or the
+            // file system
+            Add | Clean | Config | Fetch | GenerateLockfile | Help | Info | Init
+            | LocateProject | Login | Logout | Metadata | New | Owner | Pkgid | ReadManifest
+            | Remove | Report | Search | Tree | Uninstall | Update | Vendor | VerifyProject
+            | Version | Yank => false,
+            External(ref name) => external_needs_sysroot(name),
+        }
+    }
+}
+
+/// Decides whether the external subcommand `cargo-$name` needs a sysroot
+///
+/// Unknown subcommands are assumed to compile code, unless they are listed in
+/// the `XARGO_NO_SYSROOT_SUBCOMMANDS` env variable (a comma separated list of
+/// names).
+fn external_needs_sysroot(name: &str) -> bool {
+    if let Some(list) = env::var_os("XARGO_NO_SYSROOT_SUBCOMMANDS") {
+        if list.to_string_lossy().split(',').any(|s| s.trim() == name) {
+            return false;
         }
     }
+
+    match name {
+        // Well known subcommands that never invoke `rustc`
+        "audit" | "deny" | "edit" | "fmt" | "outdated" | "set-version" | "upgrade" => false,
+        _ => true,
+    }
 }
 
 impl<'a> From<&'a str> for Subcommand {
     fn from(s: &str) -> Subcommand {
+        use self::Subcommand::*;
+
         match s {
-            "clean" => Subcommand::Clean,
-            "doc" => Subcommand::Doc,
-            "init" => Subcommand::Init,
-            "new" => Subcommand::New,
-            "search" => Subcommand::Search,
-            "update" => Subcommand::Update,
-            _ => Subcommand::Other,
+            "add" => Add,
+            "bench" => Bench,
+            "build" => Build,
+            "check" => Check,
+            "clean" => Clean,
+            "config" => Config,
+            "doc" => Doc,
+            "fetch" => Fetch,
+            "fix" => Fix,
+            "generate-lockfile" => GenerateLockfile,
+            "help" => Help,
+            "info" => Info,
+            "init" => Init,
+            "install" => Install,
+            "locate-project" => LocateProject,
+            "login" => Login,
+            "logout" => Logout,
+            "metadata" => Metadata,
+            "new" => New,
+            "owner" => Owner,
+            "package" => Package,
+            "pkgid" => Pkgid,
+            "publish" => Publish,
+            "read-manifest" => ReadManifest,
+            "remove" => Remove,
+            "report" => Report,
+            "run" => Run,
+            "rustc" => Rustc,
+            "rustdoc" => Rustdoc,
+            "search" => Search,
+            "test" => Test,
+            "tree" => Tree,
+            "uninstall" => Uninstall,
+            "update" => Update,
+            "vendor" => Vendor,
+            "verify-project" => VerifyProject,
+            "version" => Version,
+            "yank" => Yank,
+            _ => External(s.to_owned()),
         }
     }
 }
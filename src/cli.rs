@@ -1,13 +1,32 @@
 use std::env;
+use std::path::PathBuf;
 
-use cargo::Subcommand;
+use cargo::{Config, Subcommand};
+use errors::*;
+
+/// Global `cargo` options that take a value
+///
+/// These can appear before the subcommand so their value must not be mistaken
+/// for the subcommand, e.g. `cargo -Z unstable-options build`
+const GLOBAL_OPTIONS_WITH_VALUE: &[&str] = &["--color", "--config", "--explain", "-Z"];
 
 pub struct Args {
     all: Vec<String>,
     subcommand: Option<Subcommand>,
+    subcommand_index: Option<usize>,
     target: Option<String>,
     message_format: Option<String>,
     manifest_path: Option<String>,  // path to the Cargo toml file given in --manifest-path
+    directory: Option<String>,  // directory given in -C
+    toolchain: Option<String>,  // rustup toolchain given as `+toolchain`
+    color: Option<String>,
+    timings: Option<String>,  // value of `--timings`, empty if none was given
+    verbose: bool,
+    quiet: bool,
+    version: bool,
+    locked: bool,
+    frozen: bool,
+    offline: bool,
 }
 
 impl Args {
@@ -15,57 +34,195 @@ impl Args {
         &self.all
     }
 
-    pub fn subcommand(&self) -> Option<Subcommand> {
-        self.subcommand
+    pub fn subcommand(&self) -> Option<&Subcommand> {
+        self.subcommand.as_ref()
     }
 
     pub fn target(&self) -> Option<&str> {
-        self.target.as_ref().map(|s| &**s)
+        self.target.as_deref()
     }
 
     pub fn message_format(&self) -> Option<&str> {
-        self.message_format.as_ref().map(|s| &**s)
+        self.message_format.as_deref()
     }
 
     pub fn verbose(&self) -> bool {
-        self.all
-            .iter()
-            .any(|a| a == "--verbose" || a == "-v" || a == "-vv")
+        self.verbose
+    }
+
+    /// Whether `-q` / `--quiet` was passed
+    pub fn quiet(&self) -> bool {
+        self.quiet
+    }
+
+    /// The value of `--color`
+    pub fn color(&self) -> Option<&str> {
+        self.color.as_deref()
+    }
+
+    /// Whether `--timings` was passed, and its value if any
+    pub fn timings(&self) -> Option<&str> {
+        self.timings.as_deref()
     }
 
     pub fn version(&self) -> bool {
-        self.all.iter().any(|a| a == "--version" || a == "-V")
+        self.version
+    }
+
+    /// Whether `--locked` or `--frozen` was passed
+    pub fn locked(&self) -> bool {
+        self.locked || self.frozen
+    }
+
+    /// Whether `--offline` or `--frozen` was passed
+    pub fn offline(&self) -> bool {
+        self.offline || self.frozen
     }
 
     pub fn manifest_path(&self) -> Option<&str> {
-        self.manifest_path.as_ref().map(|s| &**s)
+        self.manifest_path.as_deref()
+    }
+
+    /// The rustup toolchain given as `+toolchain`
+    pub fn toolchain(&self) -> Option<&str> {
+        self.toolchain.as_deref()
+    }
+
+    /// Expands the subcommand if it's an alias defined in `config`, or one of
+    /// Cargo's builtin aliases
+    pub fn expand_aliases(self, config: Option<&Config>) -> Result<Args> {
+        let mut args = self;
+        let mut expanded: Vec<String> = vec![];
+
+        loop {
+            let i = match (&args.subcommand, args.subcommand_index) {
+                // Like Cargo, don't let aliases shadow builtin subcommands
+                (&Some(Subcommand::External(_)), Some(i)) => i,
+                _ => return Ok(args),
+            };
+
+            let name = args.all[i].clone();
+            let expansion = match config {
+                Some(config) => config.alias(&name)?,
+                None => Config::builtin_alias(&name),
+            };
+
+            if let Some(expansion) = expansion {
+                if expanded.contains(&name) {
+                    bail!("alias `{}` has unresolvable recursive definition", name)
+                }
+
+                let mut all = args.all;
+                all.splice(i..i + 1, expansion);
+                expanded.push(name);
+                let toolchain = args.toolchain;
+                args = parse(all);
+                args.toolchain = toolchain;
+            } else {
+                return Ok(args);
+            }
+        }
+    }
+
+    /// The directory `cargo` will run in, i.e. the current directory adjusted
+    /// by `-C`
+    pub fn current_dir(&self) -> Result<PathBuf> {
+        let cd = env::current_dir().chain_err(|| "couldn't get the current directory")?;
+
+        Ok(match self.directory {
+            Some(ref dir) => cd.join(dir),
+            None => cd,
+        })
+    }
+}
+
+/// Splits `--option=value` into its parts
+fn split_value(arg: &str) -> (&str, Option<&str>) {
+    if arg.starts_with("--") {
+        if let Some(i) = arg.find('=') {
+            return (&arg[..i], Some(&arg[i + 1..]));
+        }
     }
+
+    (arg, None)
 }
 
 pub fn args() -> Args {
-    let all = env::args().skip(1).collect::<Vec<_>>();
+    parse(env::args().skip(1).collect())
+}
+
+fn parse(mut all: Vec<String>) -> Args {
+    // Like rustup's `cargo` proxy, accept a toolchain override as the first
+    // argument. It's not passed along as we may not be invoking a proxy.
+    let toolchain = match all.first() {
+        Some(arg) if arg.starts_with('+') => Some(arg[1..].to_owned()),
+        _ => None,
+    };
+    if toolchain.is_some() {
+        all.remove(0);
+    }
 
     let mut subcommand = None;
+    let mut subcommand_index = None;
     let mut target = None;
     let mut message_format = None;
     let mut manifest_path = None;
+    let mut directory = None;
+    let mut color = None;
+    let mut timings = None;
+    let mut verbose = false;
+    let mut quiet = false;
+    let mut version = false;
+    let mut locked = false;
+    let mut frozen = false;
+    let mut offline = false;
     {
-        let mut args = all.iter();
-        while let Some(arg) = args.next() {
-            if !arg.starts_with("-") {
-                subcommand = subcommand.or_else(|| Some(Subcommand::from(&**arg)));
+        let mut args = all.iter().enumerate();
+        while let Some((i, arg)) = args.next() {
+            // Everything after `--` is passed to the binary / test harness
+            if arg == "--" {
+                break;
             }
 
-            if arg == "--target" {
-                target = args.next().map(|s| s.to_owned());
-            } else if arg.starts_with("--target=") {
-                target = arg.splitn(2, '=').nth(1).map(|s| s.to_owned());
-            } else if arg == "--message-format" {
-                message_format = args.next().map(|s| s.to_owned());
-            } else if arg.starts_with("--message-format=") {
-                message_format = arg.splitn(2, '=').nth(1).map(|s| s.to_owned());
-            } else if arg.starts_with("--manifest-path") {
-                manifest_path = args.next().map(|s| s.to_owned());
+            if !arg.starts_with('-') {
+                if subcommand.is_none() {
+                    subcommand = Some(Subcommand::from(&**arg));
+                    subcommand_index = Some(i);
+                }
+
+                continue;
+            }
+
+            let (name, inline) = split_value(arg);
+            let mut value = || {
+                inline
+                    .map(|s| s.to_owned())
+                    .or_else(|| args.next().map(|(_, s)| s.to_owned()))
+            };
+
+            match name {
+                "--target" => target = value(),
+                "--message-format" => message_format = value(),
+                "--manifest-path" => manifest_path = value(),
+                "-v" | "-vv" | "--verbose" => verbose = true,
+                "-q" | "--quiet" => quiet = true,
+                "--color" => color = value(),
+                // The value is optional so it can only be given inline
+                "--timings" => timings = Some(inline.unwrap_or("").to_owned()),
+                "--locked" => locked = true,
+                "--frozen" => frozen = true,
+                "--offline" => offline = true,
+                "-V" | "--version" if subcommand.is_none() => version = true,
+                "-C" if subcommand.is_none() => directory = value(),
+                _ if subcommand.is_none() => {
+                    if GLOBAL_OPTIONS_WITH_VALUE.contains(&name) {
+                        value();
+                    } else if let Some(dir) = name.strip_prefix("-C") {
+                        // `-Cdir`
+                        directory = Some(dir.to_owned());
+                    }
+                }
+                _ => {}
             }
         }
     }
@@ -73,8 +230,19 @@ pub fn args() -> Args {
     Args {
         all,
         subcommand,
+        subcommand_index,
         target,
         message_format,
         manifest_path,
+        directory,
+        toolchain,
+        color,
+        timings,
+        verbose,
+        quiet,
+        version,
+        locked,
+        frozen,
+        offline,
     }
 }
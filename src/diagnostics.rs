@@ -0,0 +1,144 @@
+use std::io::{self, Write};
+use std::process::Command;
+
+use rustc_version::VersionMeta;
+
+use CompilationMode;
+use cargo::Rustflags;
+use errors::*;
+use extensions::CommandExt;
+use rustc;
+
+const MEM_FUNCTIONS: &[&str] = &["memcpy", "memmove", "memset", "memcmp", "bcmp"];
+
+const MEM_FEATURE: &str = "enable the `mem` feature of `compiler_builtins` in Xargo.toml:\n\n    \
+                           [dependencies.compiler_builtins]\n    features = [\"mem\"]\n";
+
+/// Runs one of the `cargo` invocations that build the sysroot
+///
+/// If it fails, Cargo's output is searched for known causes and the error
+/// comes with hints on how to address them.
+pub fn run(
+    cmd: &mut Command,
+    verbose: bool,
+    meta: &VersionMeta,
+    map_stdout: Option<&mut dyn FnMut(&str)>,
+) -> Result<()> {
+    // With `--message-format json` the compiler errors are printed to stdout
+    let mut stdout = String::new();
+    let (status, stderr) = match map_stdout {
+        Some(f) => cmd.run_and_tee_stderr(
+            verbose,
+            Some(&mut |line: &str| {
+                stdout.push_str(line);
+                stdout.push('\n');
+                f(line)
+            }),
+        )?,
+        None => cmd.run_and_tee_stderr(verbose, None)?,
+    };
+
+    if status.success() {
+        return Ok(());
+    }
+
+    let hints = hints(&format!("{}{}", stderr, stdout), meta);
+    Err(ErrorKind::SysrootBuildFailed(format!("{:?}", cmd), status.code(), hints))?
+}
+
+/// Returns a hint for each known cause of failure found in `output`
+fn hints(output: &str, meta: &VersionMeta) -> Vec<String> {
+    let has = |pattern: &str| output.contains(pattern);
+    let mut hints = vec![];
+
+    if (has("failed to read `") || has("failed to load manifest"))
+        && (has("library") || has("rust-src") || has("/src/lib"))
+    {
+        hints.push(
+            "the Rust source looks incomplete. Reinstall it with `rustup component add \
+             rust-src`, or point XARGO_RUST_SRC to the `library` directory of a Rust \
+             checkout"
+                .to_owned(),
+        );
+    }
+
+    if has("failed to select a version")
+        || has("no matching package named")
+        || has("failed to load source for dependency")
+        || has("failed to download")
+        || has("failed to update")
+        || has("needs to be updated but --locked was passed")
+        || has("but --offline was specified")
+    {
+        hints.push(
+            "Cargo couldn't resolve the dependencies of the sysroot. Check that `lockfile` \
+             in Xargo.toml, if set, was made for this Rust source, and see the `[offline]` \
+             section of Xargo.toml to build without network access"
+                .to_owned(),
+        );
+    }
+
+    if has("duplicate lang item") {
+        hints.push(
+            "a standard crate was built more than once, usually because it's listed in \
+             more than one stage of Xargo.toml or a crate of a later stage depends on it \
+             by path. List each crate of the Rust source in a single stage"
+                .to_owned(),
+        );
+    }
+
+    // `std` is also built as a dylib, which links
+    hints.extend(link_hints(output));
+
+    if has("E0557") || has("E0635") || has("E0658") {
+        hints.push(format!(
+            "the Rust source doesn't seem to match `rustc` ({}). Install the `rust-src` \
+             component of this toolchain, or point XARGO_RUST_SRC to the source of the same \
+             version",
+            meta.short_version_string
+        ));
+    }
+
+    hints
+}
+
+/// Returns a hint for each symbol the sysroot should have provided but that
+/// was missing when linking, according to `output`
+fn link_hints(output: &str) -> Vec<String> {
+    let missing_mem = MEM_FUNCTIONS.iter().any(|f| {
+        output.contains(&format!("undefined reference to `{}'", f))
+            || output.contains(&format!("undefined symbol: {}", f))
+    });
+
+    if missing_mem {
+        vec![format!("nothing provides `memcpy` and friends on this target; {}", MEM_FEATURE)]
+    } else {
+        vec![]
+    }
+}
+
+/// Prints a hint after the project failed to build against a sysroot whose
+/// `compiler_builtins` lacks the `mem` feature
+///
+/// Cargo keeps the terminal, so its output can't be searched for the missing
+/// symbols. The hint is only given on the targets without an OS, where nothing
+/// else provides them.
+pub fn missing_mem(
+    cmode: &CompilationMode,
+    rustflags: &Rustflags,
+    meta: &VersionMeta,
+    verbose: bool,
+) {
+    let no_os = rustc::cfg(cmode, rustflags, meta, verbose)
+        .map(|cfg| cfg.has("target_os", "none") || cfg.has("target_os", "uefi"))
+        .unwrap_or(false);
+
+    if no_os {
+        writeln!(
+            io::stderr(),
+            "help: nothing in the sysroot provides `memcpy` and friends on this target. If \
+             linking failed for lack of them, {}",
+            MEM_FEATURE
+        ).ok();
+    }
+}
@@ -1,3 +1,49 @@
 #![allow(unknown_lints)]
 #![allow(unused_doc_comments)]
-error_chain!();
+use std::path::PathBuf;
+
+error_chain! {
+    errors {
+        UnknownTarget(triple: String, suggestions: Vec<String>, searched: Vec<PathBuf>) {
+            description("unknown target")
+            display("{}", unknown_target(triple, suggestions, searched))
+        }
+        SysrootBuildFailed(command: String, code: Option<i32>, hints: Vec<String>) {
+            description("couldn't build the sysroot")
+            display("{}", sysroot_build_failed(command, code, hints))
+        }
+    }
+}
+
+fn unknown_target(triple: &str, suggestions: &[String], searched: &[PathBuf]) -> String {
+    let mut msg = format!(
+        "`{}` is not a builtin target and no `{}.json` target specification file was found",
+        triple, triple
+    );
+
+    if !suggestions.is_empty() {
+        msg.push_str("\nhelp: did you mean one of these builtin targets?");
+        for s in suggestions {
+            msg.push_str("\n  ");
+            msg.push_str(s);
+        }
+    }
+
+    msg.push_str("\nnote: target specification files were searched for in:");
+    for dir in searched {
+        msg.push_str(&format!("\n  {}", dir.display()));
+    }
+
+    msg
+}
+
+fn sysroot_build_failed(command: &str, code: &Option<i32>, hints: &[String]) -> String {
+    let mut msg = format!("`{}` failed with exit code: {:?}", command, code);
+
+    for hint in hints {
+        msg.push_str("\nhelp: ");
+        msg.push_str(hint);
+    }
+
+    msg
+}
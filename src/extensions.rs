@@ -1,31 +1,21 @@
-use std::io::Write;
+use std::io::{BufRead, BufReader, Read, Write};
 use std::io;
-use std::process::{Command, ExitStatus};
+use std::process::{Command, ExitStatus, Stdio};
+use std::thread;
 
 use errors::*;
 
 pub trait CommandExt {
-    fn run(&mut self, verbose: bool) -> Result<()>;
     fn run_and_get_status(&mut self, verbose: bool) -> Result<ExitStatus>;
     fn run_and_get_stdout(&mut self, verbose: bool) -> Result<String>;
+    fn run_and_tee_stderr(
+        &mut self,
+        verbose: bool,
+        map_stdout: Option<&mut dyn FnMut(&str)>,
+    ) -> Result<(ExitStatus, String)>;
 }
 
 impl CommandExt for Command {
-    /// Runs the command to completion
-    fn run(&mut self, verbose: bool) -> Result<()> {
-        let status = self.run_and_get_status(verbose)?;
-
-        if status.success() {
-            Ok(())
-        } else {
-            Err(format!(
-                "`{:?}` failed with exit code: {:?}",
-                self,
-                status.code()
-            ))?
-        }
-    }
-
     /// Runs the command to completion
     fn run_and_get_status(&mut self, verbose: bool) -> Result<ExitStatus> {
         if verbose {
@@ -36,6 +26,61 @@ impl CommandExt for Command {
             .chain_err(|| format!("couldn't execute `{:?}`", self))
     }
 
+    /// Runs the command to completion, printing its stderr as usual but also
+    /// returning it along with the exit status
+    ///
+    /// If `map_stdout` is given, each line of stdout is passed to it as soon
+    /// as it's printed.
+    fn run_and_tee_stderr(
+        &mut self,
+        verbose: bool,
+        map_stdout: Option<&mut dyn FnMut(&str)>,
+    ) -> Result<(ExitStatus, String)> {
+        if verbose {
+            writeln!(io::stderr(), "+ {:?}", self).ok();
+        }
+
+        if map_stdout.is_some() {
+            self.stdout(Stdio::piped());
+        }
+        let mut child = self.stderr(Stdio::piped())
+            .spawn()
+            .chain_err(|| format!("couldn't execute `{:?}`", self))?;
+
+        // stderr is read on its own thread so neither pipe can fill up
+        let stderr = child.stderr.take().map(|mut stderr| {
+            thread::spawn(move || {
+                let mut tee = vec![];
+                let mut buffer = [0; 4096];
+                loop {
+                    match stderr.read(&mut buffer) {
+                        Ok(0) | Err(_) => break,
+                        Ok(n) => {
+                            io::stderr().write_all(&buffer[..n]).ok();
+                            tee.extend_from_slice(&buffer[..n]);
+                        }
+                    }
+                }
+                String::from_utf8_lossy(&tee).into_owned()
+            })
+        });
+
+        if let (Some(f), Some(stdout)) = (map_stdout, child.stdout.take()) {
+            for line in BufReader::new(stdout).lines() {
+                f(&line.chain_err(|| format!("couldn't read the output of `{:?}`", self))?);
+            }
+        }
+
+        let status = child.wait()
+            .chain_err(|| format!("couldn't execute `{:?}`", self))?;
+        let stderr = match stderr {
+            Some(thread) => thread.join().unwrap_or_default(),
+            None => String::new(),
+        };
+
+        Ok((status, stderr))
+    }
+
     /// Runs the command to completion and returns its stdout
     fn run_and_get_stdout(&mut self, verbose: bool) -> Result<String> {
         if verbose {
@@ -6,15 +6,17 @@ extern crate fs2;
 #[cfg(any(all(target_os = "linux", not(target_env = "musl")), target_os = "macos"))]
 extern crate libc;
 extern crate rustc_version;
+#[macro_use]
 extern crate serde_json;
 extern crate tempdir;
 extern crate toml;
 extern crate walkdir;
 extern crate dirs;
 
+use std::ffi::OsStr;
 use std::hash::{Hash, Hasher};
 use std::io::Write;
-use std::path::{Path};
+use std::path::Path;
 use std::process::ExitStatus;
 use std::{env, io, process};
 
@@ -22,14 +24,20 @@ use rustc_version::Channel;
 
 use errors::*;
 use rustc::Target;
+use shell::Shell;
 
 mod cargo;
 mod cli;
+mod diagnostics;
 mod errors;
 mod extensions;
 mod flock;
+mod offline;
 mod rustc;
+mod sbom;
+mod shell;
 mod sysroot;
+mod timings;
 mod util;
 mod xargo;
 
@@ -66,6 +74,14 @@ impl CompilationMode {
         }
     }
 
+    /// The value to pass to `--target`
+    fn target_arg(&self) -> &OsStr {
+        match *self {
+            CompilationMode::Cross(ref target) => target.arg(),
+            CompilationMode::Native(ref triple) => OsStr::new(triple),
+        }
+    }
+
     fn is_native(&self) -> bool {
         match *self {
             CompilationMode::Native(_) => true,
@@ -109,8 +125,20 @@ pub fn main_inner(xargo_mode: XargoMode) {
 
 fn run(cargo_mode: XargoMode) -> Result<Option<ExitStatus>> {
     let args = cli::args();
+    let cd = args.current_dir()?;
+    let config = cargo::config(&cd)?;
+    let args = args.expand_aliases(config.as_ref())?;
     let verbose = args.verbose();
 
+    // Make every `rustc` and `cargo` we spawn, through the rustup proxies,
+    // use the requested toolchain
+    if let Some(toolchain) = args.toolchain() {
+        if verbose {
+            writeln!(io::stderr(), "+ RUSTUP_TOOLCHAIN={}", toolchain).ok();
+        }
+        env::set_var("RUSTUP_TOOLCHAIN", toolchain);
+    }
+
     let meta = rustc::version().map_err(|_| "could not determine rustc version")?;
 
     if let Some(sc) = args.subcommand() {
@@ -127,83 +155,144 @@ fn run(cargo_mode: XargoMode) -> Result<Option<ExitStatus>> {
         return cargo::run(&args, verbose).map(Some);
     }
 
-    let config = cargo::config()?;
-    if let Some(root) = cargo::root(cargo_mode, args.manifest_path())? {
-        // We can't build sysroot with stable or beta due to unstable features
+    if let Some(root) = cargo::root(cargo_mode, &cd, args.manifest_path())? {
         let sysroot = rustc::sysroot(verbose)?;
-        let src = match meta.channel {
-            Channel::Dev => rustc::Src::from_env().ok_or(
-                "The XARGO_RUST_SRC env variable must be set and point to the \
-                 Rust source directory when working with the 'dev' channel",
-            )?,
-            Channel::Nightly => if let Some(src) = rustc::Src::from_env() {
-                src
-            } else {
-                sysroot.src()?
-            },
-            Channel::Stable | Channel::Beta => {
-                eprintln!(
-                    "ERROR: the sysroot can't be built for the {:?} channel. \
-                     Switch to nightly.",
-                    meta.channel
-                );
-                process::exit(1);
-            }
-        };
+        let (xtoml_parent, xtoml) = xargo::toml(&root)?;
+        let target_path = rustc::target_path(&root, xtoml_parent, xtoml.as_ref())?;
         let cmode = if let Some(triple) = args.target() {
-            if Path::new(triple).is_file() {
-                bail!(
-                    "Xargo doesn't support files as an argument to --target. \
-                     Use `--target foo` instead of `--target foo.json`."
-                )
+            if Target::is_path(triple) {
+                CompilationMode::Cross(Target::from_path(&cd.join(triple), &meta, verbose)?)
             } else if triple == meta.host {
-                Some(CompilationMode::Native(meta.host.clone()))
+                CompilationMode::Native(meta.host.clone())
             } else {
-                Target::new(triple, &root, verbose)?.map(CompilationMode::Cross)
+                CompilationMode::Cross(Target::new(triple, &target_path, &meta, verbose)?)
             }
         } else {
             if let Some(ref config) = config {
                 if let Some(triple) = config.target()? {
-                    Target::new(triple, &root, verbose)?.map(CompilationMode::Cross)
+                    if Target::is_path(triple) {
+                        // Already made absolute by `cargo::config`
+                        CompilationMode::Cross(Target::from_path(Path::new(triple), &meta, verbose)?)
+                    } else {
+                        CompilationMode::Cross(Target::new(triple, &target_path, &meta, verbose)?)
+                    }
                 } else {
-                    Some(CompilationMode::Native(meta.host.clone()))
+                    CompilationMode::Native(meta.host.clone())
                 }
             } else {
-                Some(CompilationMode::Native(meta.host.clone()))
+                CompilationMode::Native(meta.host.clone())
             }
         };
 
-        if let Some(cmode) = cmode {
-            let home = xargo::home(&cmode)?;
-            let rustflags = cargo::rustflags(config.as_ref(), cmode.triple())?;
+        let rustflags = cargo::rustflags(config.as_ref(), cmode.triple())?;
 
-            sysroot::update(
-                &cmode,
-                &home,
-                &root,
-                &rustflags,
-                &meta,
-                &src,
-                &sysroot,
-                verbose,
-                args.message_format(),
-                cargo_mode,
-            )?;
+        if sysroot::prebuilt(&cmode, &root, &rustflags, &sysroot, cargo_mode)? {
+            if verbose {
+                writeln!(
+                    io::stderr(),
+                    "note: using the prebuilt sysroot for {}. Set XARGO_FORCE_BUILD=1 to build one.",
+                    cmode.triple()
+                ).ok();
+            }
 
             if args.subcommand().is_some() || cargo_mode == XargoMode::Build {
-                return xargo::run(
-                    &args,
-                    &cmode,
-                    rustflags,
-                    &home,
-                    &meta,
-                    config.as_ref(),
-                    verbose,
-                ).map(Some);
+                return cargo::run(&args, verbose).map(Some);
             } else {
                 return Ok(None)
             }
         }
+
+        // We can't build sysroot with stable or beta due to unstable features,
+        // unless the user opts into `RUSTC_BOOTSTRAP`
+        let bootstrap = match meta.channel {
+            Channel::Stable | Channel::Beta => {
+                if !xargo::allow_bootstrap(xtoml.as_ref())? {
+                    Err(format!(
+                        "the sysroot can't be built for the {:?} channel. Switch to nightly, \
+                         or set `allow-bootstrap = true` in Xargo.toml or the \
+                         XARGO_ALLOW_BOOTSTRAP env variable to build it with \
+                         RUSTC_BOOTSTRAP=1",
+                        meta.channel
+                    ))?
+                }
+
+                true
+            }
+            Channel::Dev | Channel::Nightly => false,
+        };
+
+        let src = match meta.channel {
+            Channel::Dev => if let Some(src) = rustc::Src::from_env() {
+                src
+            } else {
+                let src = sysroot.checkout().ok_or_else(|| {
+                    format!(
+                        "couldn't find the Rust checkout {} was built in. The \
+                         XARGO_RUST_SRC env variable must be set and point to \
+                         the Rust source directory when working with the 'dev' \
+                         channel",
+                        sysroot.path().display()
+                    )
+                })?;
+
+                if verbose {
+                    writeln!(
+                        io::stderr(),
+                        "note: using the Rust source at {}",
+                        src.path().display()
+                    ).ok();
+                }
+
+                src
+            },
+            Channel::Nightly | Channel::Stable | Channel::Beta => {
+                if let Some(src) = rustc::Src::from_env() {
+                    src
+                } else {
+                    sysroot.src()?
+                }
+            }
+        };
+
+        src.check_version(&meta, verbose)?;
+
+        let home = xargo::home(&cmode)?;
+
+        let options = sysroot::Options {
+            verbose,
+            message_format: args.message_format(),
+            cargo_mode,
+            bootstrap,
+            locked: args.locked(),
+            offline: args.offline(),
+            timings: args.timings(),
+            shell: &Shell::new(&args),
+        };
+        let builtins_mem = sysroot::update(
+            &cmode,
+            &home,
+            &root,
+            &rustflags,
+            &meta,
+            &src,
+            &sysroot,
+            config.as_ref(),
+            &options,
+        )?;
+
+        if args.subcommand().is_some() || cargo_mode == XargoMode::Build {
+            return xargo::run(
+                &args,
+                &cmode,
+                rustflags,
+                &home,
+                &meta,
+                config.as_ref(),
+                builtins_mem,
+            ).map(Some);
+        } else {
+            return Ok(None)
+        }
     }
 
     cargo::run(&args, verbose).map(Some)
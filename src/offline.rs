@@ -0,0 +1,284 @@
+use std::collections::{BTreeSet, HashSet};
+use std::{env, fs, slice};
+use std::path::{Path, PathBuf};
+
+use toml::Value;
+use toml::value::Table;
+
+use errors::*;
+use {cargo, util, xargo};
+
+/// Where the crates of an offline sysroot build come from
+enum Source {
+    /// Directory created by `cargo vendor`
+    Vendor(PathBuf),
+    /// Local registry, i.e. an `index` plus `.crate` files
+    LocalRegistry(PathBuf),
+    /// Cargo's download cache
+    Cache,
+}
+
+/// An offline sysroot build
+///
+/// Enabled by the `[offline]` section of `Xargo.toml`, the `XARGO_OFFLINE`
+/// env variable or passing `--offline` / `--frozen` to Xargo
+pub struct Offline {
+    source: Source,
+}
+
+impl Offline {
+    pub fn new(
+        xtoml: Option<&xargo::Toml>,
+        base_path: &Path,
+        offline: bool,
+    ) -> Result<Option<Offline>> {
+        let error = || "Xargo.toml: `offline` must contain either `vendor` or `local-registry`";
+
+        if let Some(offline) = xtoml.and_then(|xtoml| xtoml.offline()) {
+            let table = offline
+                .as_table()
+                .ok_or("Xargo.toml: `offline` must be a table")?;
+            let path = |key| -> Result<PathBuf> {
+                let path = table[key]
+                    .as_str()
+                    .ok_or_else(|| format!("Xargo.toml: `offline.{}` must be a string", key))?;
+                Ok(base_path.join(path))
+            };
+
+            let source = match (table.contains_key("vendor"), table.contains_key("local-registry")) {
+                (true, false) => Source::Vendor(path("vendor")?),
+                (false, true) => Source::LocalRegistry(path("local-registry")?),
+                (false, false) => Source::Cache,
+                (true, true) => Err(error())?,
+            };
+
+            return Ok(Some(Offline { source }));
+        }
+
+        if offline || env::var_os("XARGO_OFFLINE").is_some() {
+            return Ok(Some(Offline { source: Source::Cache }));
+        }
+
+        Ok(None)
+    }
+
+    /// Arguments that make `cargo` use this source without network access
+    pub fn args(&self) -> Vec<String> {
+        let mut args = vec!["--offline".to_owned()];
+
+        let (kind, path) = match self.source {
+            Source::Vendor(ref path) => ("directory", path),
+            Source::LocalRegistry(ref path) => ("local-registry", path),
+            Source::Cache => return args,
+        };
+
+        let path = Value::String(path.display().to_string());
+        args.push("--config".to_owned());
+        args.push("source.crates-io.replace-with=\"xargo-offline\"".to_owned());
+        args.push("--config".to_owned());
+        args.push(format!("source.xargo-offline.{}={}", kind, path));
+
+        args
+    }
+
+    /// Checks that every registry crate `crates` depend on, according to
+    /// `lockfile`, is available
+    ///
+    /// The registry `dependencies` of the stage that aren't in `lockfile` yet
+    /// only need some version of them to be available, as they are resolved
+    /// by Cargo later on
+    pub fn check(&self, lockfile: &Path, crates: &[String], dependencies: &Table) -> Result<()> {
+        let available = self.available()?;
+
+        let mut missing = BTreeSet::new();
+        for (name, version) in registry_dependencies(lockfile, crates)? {
+            if !available.contains(&(name.clone(), version.clone())) {
+                missing.insert(format!("{} {}", name, version));
+            }
+        }
+
+        let locked = locked_names(lockfile)?;
+        for (name, requirement) in registry_requirements(dependencies) {
+            if !locked.contains(&name) && !available.iter().any(|(n, _)| *n == name) {
+                missing.insert(format!("{} {}", name, requirement));
+            }
+        }
+
+        if missing.is_empty() {
+            return Ok(());
+        }
+
+        let from = match self.source {
+            Source::Vendor(ref path) => format!("the vendor directory {}", path.display()),
+            Source::LocalRegistry(ref path) => format!("the local registry {}", path.display()),
+            Source::Cache => "Cargo's cache".to_owned(),
+        };
+        let mut msg = format!(
+            "the sysroot can't be built offline; these crates are missing from {}:",
+            from
+        );
+        for krate in missing {
+            msg.push_str("\n    ");
+            msg.push_str(&krate);
+        }
+
+        Err(msg)?
+    }
+
+    /// Returns the name and version of every crate available in this source
+    fn available(&self) -> Result<HashSet<(String, String)>> {
+        let mut available = HashSet::new();
+
+        match self.source {
+            Source::Vendor(ref path) => {
+                for dir in read_dir(path)? {
+                    let manifest = dir.join("Cargo.toml");
+                    if !manifest.is_file() {
+                        continue;
+                    }
+
+                    let toml = util::parse(&manifest)?;
+                    let package = toml.get("package");
+                    let name = package.and_then(|p| p.get("name")).and_then(|n| n.as_str());
+                    let version = package.and_then(|p| p.get("version")).and_then(|v| v.as_str());
+                    if let (Some(name), Some(version)) = (name, version) {
+                        available.insert((name.to_owned(), version.to_owned()));
+                    }
+                }
+            }
+            Source::LocalRegistry(ref path) => {
+                available.extend(crate_files(slice::from_ref(path))?);
+            }
+            Source::Cache => {
+                if let Some(home) = cargo::home() {
+                    let cache = home.join("registry").join("cache");
+                    if cache.is_dir() {
+                        available.extend(crate_files(&read_dir(&cache)?)?);
+                    }
+                }
+            }
+        }
+
+        Ok(available)
+    }
+}
+
+fn read_dir(path: &Path) -> Result<Vec<PathBuf>> {
+    let mut entries = vec![];
+    for e in fs::read_dir(path)
+        .chain_err(|| format!("couldn't read the directory {}", path.display()))?
+    {
+        let e = e.chain_err(|| format!("couldn't read the directory {}", path.display()))?;
+        entries.push(e.path());
+    }
+
+    Ok(entries)
+}
+
+/// Returns the `(name, version)` of every `$name-$version.crate` file in `dirs`
+///
+/// As crate names can contain dashes the split is ambiguous; every possible
+/// split is returned
+fn crate_files(dirs: &[PathBuf]) -> Result<Vec<(String, String)>> {
+    let mut crates = vec![];
+    for dir in dirs {
+        for file in read_dir(dir)? {
+            let stem = match file.file_name().and_then(|f| f.to_str()) {
+                Some(f) if f.ends_with(".crate") => f.trim_end_matches(".crate").to_owned(),
+                _ => continue,
+            };
+
+            for (i, _) in stem.match_indices('-') {
+                crates.push((stem[..i].to_owned(), stem[i + 1..].to_owned()));
+            }
+        }
+    }
+
+    Ok(crates)
+}
+
+/// Returns the names of the packages in `lockfile`
+fn locked_names(lockfile: &Path) -> Result<HashSet<String>> {
+    let lock = util::parse(lockfile)?;
+
+    Ok(lock.get("package")
+        .and_then(|p| p.as_array())
+        .map(|packages| {
+            packages
+                .iter()
+                .filter_map(|p| p.get("name").and_then(|n| n.as_str()))
+                .map(|n| n.to_owned())
+                .collect()
+        })
+        .unwrap_or_default())
+}
+
+/// Returns the `(name, version requirement)` of the registry dependencies in
+/// `dependencies`, a `[dependencies]` table
+fn registry_requirements(dependencies: &Table) -> Vec<(String, String)> {
+    dependencies
+        .iter()
+        .filter_map(|(name, dependency)| match *dependency {
+            Value::String(ref requirement) => Some((name.clone(), requirement.clone())),
+            Value::Table(ref dependency)
+                if !dependency.contains_key("path") && !dependency.contains_key("git") =>
+            {
+                let name = dependency.get("package").and_then(|p| p.as_str()).unwrap_or(name);
+                dependency
+                    .get("version")
+                    .and_then(|v| v.as_str())
+                    .map(|requirement| (name.to_owned(), requirement.to_owned()))
+            }
+            _ => None,
+        })
+        .collect()
+}
+
+/// Returns the registry packages in `lockfile` that `crates` depend on,
+/// directly or not
+fn registry_dependencies(lockfile: &Path, crates: &[String]) -> Result<Vec<(String, String)>> {
+    let lock = util::parse(lockfile)?;
+    let packages = match lock.get("package").and_then(|p| p.as_array()) {
+        Some(packages) => packages,
+        None => return Ok(vec![]),
+    };
+
+    let field = |package: &Value, key: &str| {
+        package.get(key).and_then(|v| v.as_str()).unwrap_or("").to_owned()
+    };
+
+    let mut registry = vec![];
+    let mut visited = HashSet::new();
+    // `(name, version)`; an empty version matches any
+    let mut pending: Vec<(String, String)> =
+        crates.iter().map(|c| (c.clone(), String::new())).collect();
+    while let Some((name, version)) = pending.pop() {
+        for package in packages {
+            let (pname, pversion) = (field(package, "name"), field(package, "version"));
+            if pname != name || !(version.is_empty() || version == pversion) {
+                continue;
+            }
+
+            if !visited.insert((pname.clone(), pversion.clone())) {
+                continue;
+            }
+
+            let source = field(package, "source");
+            if source.starts_with("registry+") || source.starts_with("sparse+") {
+                registry.push((pname, pversion));
+            }
+
+            // `name`, `name version` or `name version (source)`
+            if let Some(deps) = package.get("dependencies").and_then(|d| d.as_array()) {
+                for dep in deps.iter().filter_map(|d| d.as_str()) {
+                    let mut parts = dep.split_whitespace();
+                    let name = parts.next().unwrap_or("").to_owned();
+                    let version = parts.next().unwrap_or("").to_owned();
+                    pending.push((name, version));
+                }
+            }
+        }
+    }
+
+    Ok(registry)
+}
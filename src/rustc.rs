@@ -1,17 +1,22 @@
 use std::env;
+use std::ffi::{OsStr, OsString};
 use std::hash::{Hash, Hasher};
+use std::io::{self, Write};
 use std::path::{Path, PathBuf};
 use std::process::Command;
 
 pub use rustc_version::version_meta as version;
 
+use rustc_version::{Channel, VersionMeta};
+
 use serde_json::Value;
 use serde_json;
 
 use errors::*;
 use extensions::CommandExt;
-use {rustc, util};
-use cargo::Root;
+use {rustc, util, xargo};
+use cargo::{Root, Rustflags};
+use CompilationMode;
 
 fn command() -> Command {
     env::var_os("RUSTC")
@@ -38,6 +43,48 @@ pub fn sysroot(verbose: bool) -> Result<Sysroot> {
             }
         })
 }
+/// `rustc $RUSTFLAGS --print cfg --target $target`
+///
+/// The flags can change the `cfg`, e.g. `-C target-feature` enables
+/// `target_has_atomic` on some targets
+pub fn cfg(
+    cmode: &CompilationMode,
+    rustflags: &Rustflags,
+    meta: &VersionMeta,
+    verbose: bool,
+) -> Result<Cfg> {
+    let mut cmd = command();
+    cmd.args(rustflags.sysroot_flags());
+    match *cmode {
+        CompilationMode::Cross(Target::Custom { ref json, .. })
+        | CompilationMode::Cross(Target::Path { ref json, .. }) => {
+            unstable_options(&mut cmd, meta);
+            cmd.args(&["--print", "cfg", "--target"]).arg(json);
+        }
+        _ => {
+            cmd.args(&["--print", "cfg", "--target", cmode.triple()]);
+        }
+    }
+
+    cmd.run_and_get_stdout(verbose)
+        .map(|out| Cfg {
+            lines: out.lines().map(|l| l.trim().to_owned()).collect(),
+        })
+}
+
+/// The `cfg` values that are set for a target
+pub struct Cfg {
+    lines: Vec<String>,
+}
+
+impl Cfg {
+    /// Whether `key="value"` is set
+    pub fn has(&self, key: &str, value: &str) -> bool {
+        let cfg = format!("{}=\"{}\"", key, value);
+        self.lines.iter().any(|l| *l == cfg)
+    }
+}
+
 /// Path to Rust source
 pub struct Src {
     path: PathBuf,
@@ -57,6 +104,106 @@ impl Src {
     pub fn path(&self) -> &Path {
         &self.path
     }
+
+    /// Returns the commit hash, and date if known, this source was taken from
+    ///
+    /// Source tarballs and the `rust-src` component record it next to the
+    /// `library` (or `src`) directory in `git-commit-info`, `git-commit-hash`
+    /// or `version`.
+    fn commit(&self) -> Result<Option<(String, Option<String>)>> {
+        let root = match self.path.parent() {
+            Some(root) => root,
+            None => return Ok(None),
+        };
+
+        let info = root.join("git-commit-info");
+        if info.is_file() {
+            // hash, short hash, date
+            let info = util::read(&info)?;
+            let mut lines = info.lines().map(|l| l.trim().to_owned());
+            if let Some(hash) = lines.next() {
+                return Ok(Some((hash, lines.nth(1))));
+            }
+        }
+
+        let hash = root.join("git-commit-hash");
+        if hash.is_file() {
+            return Ok(Some((util::read(&hash)?.trim().to_owned(), None)));
+        }
+
+        let version = root.join("version");
+        if version.is_file() {
+            // e.g. `1.76.0-nightly (a1b2c3d4e 2023-12-01)`
+            let version = util::read(&version)?;
+            if let (Some(start), Some(end)) = (version.find('('), version.rfind(')')) {
+                if start < end {
+                    let mut parts = version[start + 1..end].split_whitespace();
+                    if let Some(hash) = parts.next() {
+                        return Ok(Some((hash.to_owned(), parts.next().map(|d| d.to_owned()))));
+                    }
+                }
+            }
+        }
+
+        Ok(None)
+    }
+
+    /// Checks that this source is the one `rustc` was built from
+    ///
+    /// A mismatch is an error unless the `XARGO_IGNORE_SRC_VERSION` env
+    /// variable is set, in which case it's only a warning.
+    pub fn check_version(&self, meta: &VersionMeta, verbose: bool) -> Result<()> {
+        let rustc_hash = match meta.commit_hash {
+            Some(ref hash) => hash,
+            None => return Ok(()),
+        };
+
+        let (src_hash, src_date) = match self.commit()? {
+            Some(commit) => commit,
+            None => {
+                if verbose {
+                    writeln!(
+                        io::stderr(),
+                        "note: couldn't determine the version of the Rust source at {}",
+                        self.path.display()
+                    ).ok();
+                }
+
+                return Ok(());
+            }
+        };
+
+        // `version` only records the short hash
+        if src_hash.is_empty()
+            || rustc_hash.starts_with(&src_hash)
+            || src_hash.starts_with(&**rustc_hash)
+        {
+            return Ok(());
+        }
+
+        let describe = |hash: &str, date: Option<&str>| match date {
+            Some(date) => format!("{} ({})", hash, date),
+            None => hash.to_owned(),
+        };
+        let msg = format!(
+            "the Rust source at {} is from commit {} but rustc was built from commit {}. \
+             Install the `rust-src` component of your toolchain or point XARGO_RUST_SRC \
+             to the matching source",
+            self.path.display(),
+            describe(&src_hash, src_date.as_deref()),
+            describe(rustc_hash, meta.commit_date.as_deref()),
+        );
+
+        if env::var_os("XARGO_IGNORE_SRC_VERSION").is_some() {
+            writeln!(io::stderr(), "warning: {}", msg).ok();
+            Ok(())
+        } else {
+            Err(format!(
+                "{}. Set the XARGO_IGNORE_SRC_VERSION env variable to build the sysroot anyway",
+                msg
+            ))?
+        }
+    }
 }
 
 /// Path to `rustc`'s sysroot
@@ -91,52 +238,149 @@ impl Sysroot {
              rust-src`.",
         )?
     }
+
+    /// Returns the Rust source of the checkout this sysroot was built in, if
+    /// any
+    ///
+    /// A locally built compiler lives in `$CHECKOUT/build/$HOST/stage1`, so
+    /// the sysroot and its parent directories are searched for a `library` or
+    /// `src/libstd` directory.
+    pub fn checkout(&self) -> Option<Src> {
+        let path = self.path().canonicalize().unwrap_or_else(|_| self.path.clone());
+
+        for dir in path.ancestors() {
+            if dir.join("library").join("std").join("Cargo.toml").is_file() {
+                return Some(Src {
+                    path: dir.join("library"),
+                });
+            }
+
+            if dir.join("src").join("libstd").join("Cargo.toml").is_file() {
+                return Some(Src {
+                    path: dir.join("src"),
+                });
+            }
+        }
+
+        None
+    }
+}
+
+/// Returns the directories searched for target specification files, in order
+///
+/// - The project root
+/// - Every entry of `RUST_TARGET_PATH`
+/// - `.cargo/targets` in the project root
+/// - `target-path` in `Xargo.toml`, relative to `Xargo.toml`
+pub fn target_path(root: &Root, xtoml_parent: Option<&Path>, xtoml: Option<&xargo::Toml>) -> Result<Vec<PathBuf>> {
+    let mut dirs = vec![root.path().to_path_buf()];
+
+    if let Some(paths) = env::var_os("RUST_TARGET_PATH") {
+        dirs.extend(env::split_paths(&paths));
+    }
+
+    dirs.push(root.path().join(".cargo").join("targets"));
+
+    if let Some(xtoml) = xtoml {
+        let base = xtoml_parent.unwrap_or_else(|| root.path());
+        dirs.extend(xtoml.target_path()?.into_iter().map(|p| base.join(p)));
+    }
+
+    Ok(dirs)
+}
+
+/// Value of `RUST_TARGET_PATH` that lets `rustc` find `json` even when it's
+/// invoked from another directory
+pub fn rust_target_path(json: &Path) -> Result<OsString> {
+    let mut paths = vec![json.parent().unwrap().to_path_buf()];
+    if let Some(p) = env::var_os("RUST_TARGET_PATH") {
+        paths.extend(env::split_paths(&p));
+    }
+
+    env::join_paths(paths).chain_err(|| "couldn't build the RUST_TARGET_PATH env variable")
 }
 
 #[derive(Debug)]
 pub enum Target {
     Builtin { triple: String },
-    Custom { json: PathBuf, triple: String },
+    /// `spec` is the specification file as normalized by `rustc`
+    Custom { json: PathBuf, triple: String, spec: Value },
+    /// A target specification file given by path, e.g. `--target foo.json`
+    Path { json: PathBuf, triple: String, spec: Value },
 }
 
 impl Target {
-    pub fn new(triple: &str, root: &Root, verbose: bool) -> Result<Option<Target>> {
+    /// Looks `triple` up in the builtin targets, and then for a `$triple.json`
+    /// specification file in each of the `search_path` directories
+    pub fn new(
+        triple: &str,
+        search_path: &[PathBuf],
+        meta: &VersionMeta,
+        verbose: bool,
+    ) -> Result<Target> {
         let triple = triple.to_owned();
 
-        if rustc::targets(verbose)?.iter().any(|t| t == &triple) {
-            Ok(Some(Target::Builtin { triple: triple }))
-        } else {
-            let mut json = root.path().join(&triple);
+        let targets = rustc::targets(verbose)?;
+        if targets.iter().any(|t| t == &triple) {
+            return Ok(Target::Builtin { triple });
+        }
+
+        for dir in search_path {
+            let mut json = dir.join(&triple);
             json.set_extension("json");
 
             if json.exists() {
-                return Ok(Some(Target::Custom {
-                    json: json,
-                    triple: triple,
-                }));
-            } else {
-                if let Some(p) = env::var_os("RUST_TARGET_PATH") {
-                    let mut json = PathBuf::from(p);
-                    json.push(&triple);
-                    json.set_extension("json");
-
-                    if json.exists() {
-                        return Ok(Some(Target::Custom {
-                            json: json,
-                            triple: triple,
-                        }));
-                    }
-                }
+                let spec = target_spec(&json, meta, verbose)?;
+                return Ok(Target::Custom { json, triple, spec });
             }
-
-            Ok(None)
         }
+
+        // Suggest the builtin targets that are closest to what was typed
+        let mut suggestions = targets
+            .into_iter()
+            .map(|t| (util::edit_distance(&t, &triple), t))
+            .filter(|&(d, _)| d <= triple.len() / 3)
+            .collect::<Vec<_>>();
+        suggestions.sort();
+        let suggestions = suggestions.into_iter().take(3).map(|(_, t)| t).collect();
+
+        Err(ErrorKind::UnknownTarget(triple, suggestions, search_path.to_vec()).into())
+    }
+
+    /// Like Cargo, treats `target` as a path to a target specification file
+    /// if it ends in `.json`
+    pub fn is_path(target: &str) -> bool {
+        target.ends_with(".json")
+    }
+
+    /// Creates a target from the path to its specification file. The name of
+    /// the file, sans extension, is the target triple.
+    pub fn from_path(json: &Path, meta: &VersionMeta, verbose: bool) -> Result<Target> {
+        let json = json.canonicalize().chain_err(|| {
+            format!("couldn't find the target specification file {}", json.display())
+        })?;
+        let triple = json.file_stem()
+            .and_then(|s| s.to_str())
+            .ok_or_else(|| format!("{} is not a valid target specification file name", json.display()))?
+            .to_owned();
+        let spec = target_spec(&json, meta, verbose)?;
+
+        Ok(Target::Path { json, triple, spec })
     }
 
     pub fn triple(&self) -> &str {
         match *self {
             Target::Builtin { ref triple } => triple,
             Target::Custom { ref triple, .. } => triple,
+            Target::Path { ref triple, .. } => triple,
+        }
+    }
+
+    /// The value to pass to `--target`
+    pub fn arg(&self) -> &OsStr {
+        match *self {
+            Target::Path { ref json, .. } => json.as_os_str(),
+            _ => OsStr::new(self.triple()),
         }
     }
 
@@ -144,15 +388,63 @@ impl Target {
     where
         H: Hasher,
     {
-        if let Target::Custom { ref json, .. } = *self {
-            // Here we roundtrip to/from JSON to get the same hash when some
-            // fields of the JSON file has been shuffled around
-            serde_json::from_str::<Value>(&util::read(json)?)
-                .chain_err(|| format!("{} is not valid JSON", json.display()))?
-                .to_string()
-                .hash(hasher);
+        match *self {
+            Target::Builtin { .. } => {}
+            // `rustc` fills in the default values, so the hash only changes
+            // when the meaning of the specification does
+            Target::Custom { ref spec, .. } => spec.to_string().hash(hasher),
+            Target::Path { ref json, ref spec, .. } => {
+                // Two specification files with the same name may live in
+                // different directories
+                json.hash(hasher);
+                spec.to_string().hash(hasher);
+            }
         }
 
         Ok(())
     }
 }
+
+/// Passes `-Z unstable-options`, which custom targets need
+///
+/// This is only used to print information about the target, which we also
+/// need to do on stable and beta toolchains
+fn unstable_options(cmd: &mut Command, meta: &VersionMeta) {
+    cmd.args(&["-Z", "unstable-options"]);
+    match meta.channel {
+        Channel::Stable | Channel::Beta => {
+            cmd.env("RUSTC_BOOTSTRAP", "1");
+        }
+        Channel::Dev | Channel::Nightly => {}
+    }
+}
+
+/// `rustc -Z unstable-options --print target-spec-json --target $json`
+///
+/// This validates the specification file and returns it normalized, i.e. with
+/// all the default values filled in
+fn target_spec(json: &Path, meta: &VersionMeta, verbose: bool) -> Result<Value> {
+    let mut cmd = command();
+    unstable_options(&mut cmd, meta);
+    cmd.args(&["--print", "target-spec-json", "--target"]).arg(json);
+
+    if verbose {
+        writeln!(io::stderr(), "+ {:?}", cmd).ok();
+    }
+
+    let out = cmd.output()
+        .chain_err(|| format!("couldn't execute `{:?}`", cmd))?;
+
+    if !out.status.success() {
+        Err(format!(
+            "{} is not a valid target specification file:\n{}",
+            json.display(),
+            String::from_utf8_lossy(&out.stderr).trim()
+        ))?
+    }
+
+    // Here we roundtrip to/from JSON so that the order of the fields doesn't
+    // matter
+    serde_json::from_slice(&out.stdout)
+        .chain_err(|| format!("`{:?}` didn't output valid JSON", cmd))
+}
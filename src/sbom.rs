@@ -0,0 +1,104 @@
+use std::collections::BTreeMap;
+use std::path::Path;
+
+use rustc_version::VersionMeta;
+use serde_json::{self, Value};
+
+use errors::*;
+use util;
+
+/// Returns the CycloneDX components of the packages `cargo metadata` reports,
+/// but the project Xargo creates for each stage
+pub fn components(metadata: &str) -> Result<Vec<Value>> {
+    let metadata = serde_json::from_str::<Value>(metadata)
+        .chain_err(|| "`cargo metadata` didn't output valid JSON")?;
+
+    let members = metadata["workspace_members"]
+        .as_array()
+        .map(|m| m.iter().filter_map(|id| id.as_str()).collect::<Vec<_>>())
+        .unwrap_or_default();
+    let packages = metadata["packages"].as_array().map(|p| &p[..]).unwrap_or(&[]);
+
+    Ok(packages
+        .iter()
+        .filter(|p| match p["id"].as_str() {
+            Some(id) => !members.contains(&id),
+            None => true,
+        })
+        .map(component)
+        .collect())
+}
+
+/// The CycloneDX component of a package reported by `cargo metadata`
+fn component(package: &Value) -> Value {
+    let name = package["name"].as_str().unwrap_or("");
+    let version = package["version"].as_str().unwrap_or("");
+
+    // The Rust source, a git repository or a registry
+    let source = match package["source"].as_str() {
+        Some(source) => source.to_owned(),
+        None => package["manifest_path"]
+            .as_str()
+            .and_then(|m| Path::new(m).parent())
+            .map(|dir| format!("path+{}", dir.display()))
+            .unwrap_or_default(),
+    };
+
+    let mut component = json!({
+        "type": "library",
+        "bom-ref": package["id"],
+        "name": name,
+        "version": version,
+        "properties": [{ "name": "xargo:source", "value": source }],
+    });
+
+    if source.starts_with("registry+") || source.starts_with("sparse+") {
+        component["purl"] = json!(format!("pkg:cargo/{}@{}", name, version));
+    } else if let Some(url) = source.strip_prefix("git+") {
+        component["externalReferences"] = json!([{ "type": "vcs", "url": url }]);
+    }
+
+    if let Some(license) = package["license"].as_str() {
+        component["licenses"] = json!([{ "expression": license }]);
+    } else if let Some(file) = package["license_file"].as_str() {
+        component["licenses"] = json!([{ "license": { "name": file } }]);
+    }
+
+    component
+}
+
+/// Writes a CycloneDX SBOM of the sysroot of `triple` made of the components
+/// of all its stages
+pub fn write(path: &Path, triple: &str, meta: &VersionMeta, stages: &[&[Value]]) -> Result<()> {
+    let mut components = BTreeMap::new();
+    for component in stages.iter().flat_map(|stage| stage.iter()) {
+        let id = component["bom-ref"].as_str().unwrap_or("").to_owned();
+        components.entry(id).or_insert_with(|| component.clone());
+    }
+
+    let sbom = json!({
+        "bomFormat": "CycloneDX",
+        "specVersion": "1.4",
+        "version": 1,
+        "metadata": {
+            "tools": [{ "name": "xargo", "version": env!("CARGO_PKG_VERSION") }],
+            "component": {
+                "type": "library",
+                "bom-ref": format!("sysroot-{}", triple),
+                "name": format!("sysroot-{}", triple),
+                "properties": [
+                    { "name": "xargo:target", "value": triple },
+                    { "name": "rustc:version", "value": meta.short_version_string },
+                    {
+                        "name": "rustc:commit-hash",
+                        "value": meta.commit_hash.as_deref().unwrap_or("unknown"),
+                    },
+                ],
+            },
+        },
+        "components": components.into_values().collect::<Vec<_>>(),
+    });
+
+    let json = serde_json::to_string_pretty(&sbom).chain_err(|| "couldn't serialize the SBOM")?;
+    util::write(path, &json)
+}
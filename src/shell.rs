@@ -0,0 +1,79 @@
+use std::env;
+use std::io::{self, IsTerminal, Write};
+
+use cli::Args;
+
+#[derive(Clone, Copy, PartialEq)]
+enum Verbosity {
+    Quiet,
+    Normal,
+    Verbose,
+}
+
+/// Where Xargo's own status lines go, in the style of Cargo's
+pub struct Shell {
+    verbosity: Verbosity,
+    /// `--color` as given on the command line
+    color: Option<String>,
+    /// Whether the status lines are colored
+    colored: bool,
+}
+
+impl Shell {
+    pub fn new(args: &Args) -> Shell {
+        let verbosity = if args.verbose() {
+            Verbosity::Verbose
+        } else if args.quiet() {
+            Verbosity::Quiet
+        } else {
+            Verbosity::Normal
+        };
+
+        let color = args.color().map(|c| c.to_owned());
+        let choice = color.clone().or_else(|| env::var("CARGO_TERM_COLOR").ok());
+        let colored = match choice.as_deref() {
+            Some("always") => true,
+            Some("never") => false,
+            _ => io::stderr().is_terminal(),
+        };
+
+        Shell { verbosity, color, colored }
+    }
+
+    /// Prints a status line like Cargo's `   Compiling foo v0.1.0`, unless
+    /// `-q` was passed
+    pub fn status(&self, verb: &str, message: &str) {
+        if self.verbosity == Verbosity::Quiet {
+            return;
+        }
+
+        if self.colored {
+            writeln!(io::stderr(), "\x1b[1;32m{:>12}\x1b[0m {}", verb, message).ok();
+        } else {
+            writeln!(io::stderr(), "{:>12} {}", verb, message).ok();
+        }
+    }
+
+    /// Arguments that give the `cargo` that builds the sysroot the right
+    /// verbosity and colors
+    ///
+    /// Unless `-v` was passed, Cargo's output is replaced by Xargo's status
+    /// lines.
+    pub fn cargo_args(&self) -> Vec<&str> {
+        let mut args = match self.verbosity {
+            Verbosity::Verbose => vec!["-v"],
+            Verbosity::Normal | Verbosity::Quiet => vec!["-q"],
+        };
+
+        // Cargo's stderr goes through a pipe, so it can't tell it's a terminal
+        if let Some(ref color) = self.color {
+            args.push("--color");
+            args.push(color);
+        } else if self.colored {
+            args.push("--color");
+            args.push("always");
+        }
+
+        args
+    }
+}
@@ -1,22 +1,26 @@
-use std::collections::BTreeMap;
+use std::collections::{BTreeMap, BTreeSet};
 use std::collections::hash_map::DefaultHasher;
 use std::hash::{Hash, Hasher};
 use std::io::{self, Write};
 use std::path::{Path, PathBuf};
+use std::time::Instant;
 use std::{env, fs};
 
 use rustc_version::VersionMeta;
+use serde_json::{self, Value as JsonValue};
 use tempdir::TempDir;
 use toml::{value::Table, Value, map::Map};
 
 use CompilationMode;
-use cargo::{Root, Rustflags};
+use cargo::{Config, Root, Rustflags};
 use errors::*;
 use extensions::CommandExt;
+use offline::Offline;
 use rustc::{Src, Sysroot, Target};
-use util;
+use shell::Shell;
+use timings::Timings;
 use xargo::Home;
-use {cargo, xargo};
+use {cargo, diagnostics, rustc, sbom, timings, util, xargo};
 
 fn profile() -> &'static str {
     "release"
@@ -30,11 +34,11 @@ fn build(
     rustflags: &Rustflags,
     src: &Src,
     sysroot: &Sysroot,
-    hash: u64,
-    verbose: bool,
-    message_format: Option<&str>,
-    cargo_mode: XargoMode,
-) -> Result<()> {
+    hashes: &[u64],
+    meta: &VersionMeta,
+    sources: &Sources,
+    options: &Options,
+) -> Result<Vec<timings::Stage>> {
     const TOML: &'static str = r#"
 [package]
 authors = ["The Rust Project Developers"]
@@ -42,14 +46,84 @@ name = "sysroot"
 version = "0.0.0"
 "#;
 
+    let Options { verbose, message_format, cargo_mode, bootstrap, locked, shell, timings, .. } =
+        *options;
+    let lockfile = &sources.lockfile;
+    let offline = sources.offline.as_ref();
+    let stage_config = sources.stage_config.as_ref();
+
+    if bootstrap {
+        writeln!(
+            io::stderr(),
+            "warning: building the sysroot with RUSTC_BOOTSTRAP=1. It relies on \
+             unstable features which may break with any toolchain update."
+        ).ok();
+    }
+
+    match *lockfile {
+        Lockfile::Xargo(ref path) => if verbose {
+            writeln!(io::stderr(), "note: using the lockfile {}", path.display()).ok();
+        },
+        Lockfile::Src(ref path) => if verbose {
+            writeln!(
+                io::stderr(),
+                "note: using the lockfile of the Rust source, {}",
+                path.display()
+            ).ok();
+        },
+        Lockfile::Generated => {
+            writeln!(
+                io::stderr(),
+                "warning: the Rust source at {} has no Cargo.lock; generating one \
+                 offline. Set `lockfile` in Xargo.toml to pin the sysroot dependencies.",
+                src.path().display()
+            ).ok();
+        }
+    }
+
     let rustlib = home.lock_rw(cmode.triple())?;
-    rustlib
-        .remove_siblings()
-        .chain_err(|| format!("couldn't clear {}", rustlib.path().display()))?;
     let dst = rustlib.parent().join("lib");
-    util::mkdir(&dst)?;
+    let ffile = rustlib.parent().join(".fingerprint");
+
+    // Keep the artifacts of the stages that are still fresh and only rebuild
+    // the stages that come after them
+    let mut installed = read_installed(&ffile)?;
+    let recorded = installed.len();
+    let fresh = fresh_stages(&installed, hashes);
+    if fresh > 0 && installed[fresh..].iter().any(|stale| {
+        stale.files.iter().any(|f| {
+            installed[..fresh].iter().any(|kept| kept.files.contains(f))
+        })
+    }) {
+        // A stale stage overwrote an artifact of a fresh stage; we can't
+        // restore the original so we have to start from scratch
+        installed.clear();
+    } else {
+        for stale in installed.drain(fresh..) {
+            let lock = stage_lockfile(rustlib.parent(), stale.stage);
+            for path in stale.files.iter().map(|f| dst.join(f)).chain(Some(lock)) {
+                if path.exists() {
+                    fs::remove_file(&path)
+                        .chain_err(|| format!("couldn't remove {}", path.display()))?;
+                }
+            }
+        }
+    }
+
+    if installed.is_empty() {
+        rustlib
+            .remove_siblings()
+            .chain_err(|| format!("couldn't clear {}", rustlib.path().display()))?;
+        util::mkdir(&dst)?;
+    }
+
+    // The stages that were removed from `Xargo.toml` are gone for good, even
+    // if no stage has to be built
+    if installed.len() != recorded {
+        save(rustlib.parent(), cmode.triple(), meta, &installed)?;
+    }
 
-    if cmode.triple().contains("pc-windows-gnu") && cargo_mode == XargoMode::Build {
+    if installed.is_empty() && cmode.triple().contains("pc-windows-gnu") && cargo_mode == XargoMode::Build {
         let src = &sysroot
             .path()
             .join("lib")
@@ -88,7 +162,35 @@ version = "0.0.0"
         }
     }
 
-    for (_, stage) in blueprint.stages {
+    let rust_target_path = match *cmode {
+        CompilationMode::Cross(Target::Custom { ref json, .. }) => {
+            Some(rustc::rust_target_path(json)?)
+        }
+        _ => None,
+    };
+
+    let skip = installed.len();
+    let json = is_json(message_format);
+    if json {
+        event(
+            "xargo-sysroot-build-started",
+            cmode.triple(),
+            json!({ "stages": hashes.len(), "fresh-stages": skip }),
+        );
+    }
+
+    let stages = hashes.len();
+    let start = Instant::now();
+    let mut stage_timings = vec![];
+    let built = blueprint.stages.into_iter().zip(hashes).enumerate().skip(skip);
+    for (i, ((n, stage), hash)) in built {
+        shell.status(
+            "Building",
+            &format!("sysroot for {}, stage {} ({}/{})", cmode.triple(), n, i + 1, stages),
+        );
+        let stage_start = Instant::now();
+        let mut crate_timings = vec![];
+
         let td = TempDir::new("xargo").chain_err(|| "couldn't create a temporary directory")?;
         let tdp;
         let td = if env::var_os("XARGO_KEEP_TEMP").is_some() {
@@ -102,7 +204,7 @@ version = "0.0.0"
         {
             let mut map = Table::new();
 
-            map.insert("dependencies".to_owned(), Value::Table(stage.dependencies));
+            map.insert("dependencies".to_owned(), Value::Table(stage.dependencies.clone()));
             map.insert("patch".to_owned(), Value::Table(stage.patch));
 
             stoml.push_str(&Value::Table(map).to_string());
@@ -114,25 +216,108 @@ version = "0.0.0"
             }
         }
 
-        // rust-src comes with a lockfile for libstd. Use it.
-        let src_parent = src.path().parent().map(Path::to_path_buf).unwrap_or_else(|| src.path().join(".."));
-        let lockfile = src_parent.join("Cargo.lock");
-        let target_lockfile = td.join("Cargo.lock");
-        fs::copy(lockfile, &target_lockfile).chain_err(|| "Cargo.lock file is missing from source dir")?;
-
-        let mut perms = fs::metadata(&target_lockfile)
-            .chain_err(|| "Cargo.lock file is missing from target dir")?
-            .permissions();
-        perms.set_readonly(false);
-        fs::set_permissions(&target_lockfile, perms)
-            .chain_err(|| "Cargo.lock file is missing from target dir")?;
-
         util::write(&td.join("Cargo.toml"), &stoml)?;
         util::mkdir(&td.join("src"))?;
         util::write(&td.join("src").join("lib.rs"), "")?;
 
+        if let Some(config) = stage_config {
+            util::mkdir(&td.join(".cargo"))?;
+            util::write(
+                &td.join(".cargo").join("config.toml"),
+                &Value::Table(config.clone()).to_string(),
+            )?;
+        }
+
+        // Cargo is run from the stage directory so that the project's
+        // `.cargo/config` doesn't apply, only the sections copied above. From
+        // there rustup can't see the project's `rust-toolchain` file, so the
+        // `cargo` and `rustc` of `rustc`'s own sysroot are used instead.
+        let command = || {
+            let mut cmd = cargo::toolchain_command(sysroot.path());
+            cmd.current_dir(td);
+
+            // The user opted into building the sysroot with a stable or beta
+            // toolchain. Only these invocations get to use unstable features.
+            if bootstrap {
+                cmd.env("RUSTC_BOOTSTRAP", "1");
+            }
+
+            cmd
+        };
+
+        let target_lockfile = td.join("Cargo.lock");
+        match *lockfile {
+            Lockfile::Xargo(ref lockfile) | Lockfile::Src(ref lockfile) => {
+                fs::copy(lockfile, &target_lockfile).chain_err(|| {
+                    format!("couldn't copy {}", lockfile.display())
+                })?;
+
+                let mut perms = fs::metadata(&target_lockfile)
+                    .chain_err(|| "Cargo.lock file is missing from target dir")?
+                    .permissions();
+                perms.set_readonly(false);
+                fs::set_permissions(&target_lockfile, perms)
+                    .chain_err(|| "Cargo.lock file is missing from target dir")?;
+            }
+            Lockfile::Generated => {
+                if locked {
+                    Err(format!(
+                        "the Rust source at {} has no Cargo.lock, so the sysroot \
+                         can't be built with `--locked`. Set `lockfile` in Xargo.toml",
+                        src.path().display()
+                    ))?
+                }
+
+                // Only the crates in the Rust source, and those already in
+                // Cargo's cache, can be used so the resolution doesn't depend
+                // on what was published to crates.io last
+                let mut cmd = command();
+                cmd.args(&["generate-lockfile", "--offline", "--manifest-path"]);
+                cmd.arg(td.join("Cargo.toml"));
+                if let Some(offline) = offline {
+                    cmd.args(offline.args().iter().skip(1));
+                }
+                diagnostics::run(&mut cmd, verbose, meta, None)
+                    .chain_err(|| "couldn't generate a lockfile for the sysroot")?;
+            }
+        }
+
+        // Report every crate that can't be found, rather than the first one
+        // Cargo would try to download
+        if let Some(offline) = offline {
+            offline.check(&target_lockfile, &stage.crates, &stage.dependencies)?;
+        }
+
+        // The stage project isn't the workspace the lockfile was made for, so
+        // Cargo always has to update it and `--locked` would always fail.
+        // Instead resolve the stage and check that nothing was added.
+        if locked {
+            let original = lock_packages(&target_lockfile)?;
+
+            let mut cmd = command();
+            cmd.args(&["metadata", "--format-version", "1", "--manifest-path"]);
+            cmd.arg(td.join("Cargo.toml"));
+            if let Some(offline) = offline {
+                cmd.args(offline.args());
+            }
+            cmd.run_and_get_stdout(verbose)?;
+
+            let added = lock_packages(&target_lockfile)?
+                .into_iter()
+                .filter(|p| !original.contains(p) && p.0 != "sysroot")
+                .map(|(name, version, source)| format!("\n    {} {} {}", name, version, source))
+                .collect::<String>();
+            if !added.is_empty() {
+                Err(format!(
+                    "the sysroot lockfile needs to be updated but `--locked` was passed. \
+                     These packages aren't in the lockfile:{}",
+                    added
+                ))?
+            }
+        }
+
         let cargo = || {
-            let mut cmd = cargo::command();
+            let mut cmd = command();
             let mut flags = rustflags.clone();
             flags.push(&["-Z", "force-unstable-if-unmarked"]);
             if verbose {
@@ -140,8 +325,8 @@ version = "0.0.0"
             }
             cmd.env("CARGO_ENCODED_RUSTFLAGS", flags.encode(home));
 
-            // Since we currently don't want to respect `.cargo/config` or `CARGO_TARGET_DIR`,
-            // we need to force the target directory to match the `cp_r` below.
+            // Since we currently don't want to respect `CARGO_TARGET_DIR`, we
+            // need to force the target directory to match the `cp_r` below.
             cmd.env("CARGO_TARGET_DIR", td.join("target"));
 
             // Workaround #261.
@@ -174,13 +359,9 @@ version = "0.0.0"
             // source is provided as a workspace and Cargo will change the current directory to the
             // root of the workspace when building one. To ensure rustc finds a target specification
             // file stored in the current directory we'll set `RUST_TARGET_PATH`  to the current
-            // directory.
-            if env::var_os("RUST_TARGET_PATH").is_none() {
-                if let CompilationMode::Cross(ref target) = *cmode {
-                    if let Target::Custom { ref json, .. } = *target {
-                        cmd.env("RUST_TARGET_PATH", json.parent().unwrap());
-                    }
-                }
+            // directory, in front of the directories the user may have set.
+            if let Some(ref path) = rust_target_path {
+                cmd.env("RUST_TARGET_PATH", path);
             }
 
             match cargo_mode {
@@ -191,69 +372,523 @@ version = "0.0.0"
             cmd.arg("--release");
             cmd.arg("--manifest-path");
             cmd.arg(td.join("Cargo.toml"));
-            cmd.args(&["--target", cmode.triple()]);
+            cmd.arg("--target");
+            cmd.arg(cmode.target_arg());
             if let Some(format) = message_format {
                 cmd.args(&["--message-format", format]);
             }
 
-            if verbose {
-                cmd.arg("-v");
+            if let Some(offline) = offline {
+                cmd.args(offline.args());
+            }
+
+            cmd.args(shell.cargo_args());
+
+            match timings {
+                Some("") => {
+                    cmd.arg("--timings");
+                }
+                Some(timings) => {
+                    cmd.arg(format!("--timings={}", timings));
+                }
+                None => {}
             }
 
             cmd
         };
 
-        for krate in stage.crates {
-            cargo().arg("-p").arg(krate).run(verbose)?;
+        // Cargo's own progress bar is silenced, so report each crate instead
+        let crates = stage.crates.len();
+        for (j, krate) in stage.crates.into_iter().enumerate() {
+            shell.status("Compiling", &format!("{} ({}/{})", krate, j + 1, crates));
+            let crate_start = Instant::now();
+            let mut cmd = cargo();
+            cmd.arg("-p").arg(&krate);
+
+            if json {
+                // Keep the sysroot's messages apart from the project's
+                diagnostics::run(&mut cmd, verbose, meta, Some(&mut |line: &str| {
+                    let message = serde_json::from_str::<JsonValue>(line)
+                        .unwrap_or_else(|_| JsonValue::String(line.to_owned()));
+                    event(
+                        "xargo-sysroot-message",
+                        cmode.triple(),
+                        json!({ "stage": n, "message": message }),
+                    );
+                }))?;
+            } else {
+                diagnostics::run(&mut cmd, verbose, meta, None)?;
+            }
+
+            crate_timings.push((krate, crate_start.elapsed()));
         }
 
         // Copy artifacts to Xargo sysroot
-        util::cp_r(
-            &td.join("target")
-                .join(cmode.triple())
-                .join(profile())
-                .join("deps"),
-            &dst,
-        )?;
+        let deps = td.join("target")
+            .join(cmode.triple())
+            .join(profile())
+            .join("deps");
+        util::cp_r(&deps, &dst)?;
+
+        // Record which artifacts this stage installed so that they can be
+        // removed if the stage has to be rebuilt later on
+        let mut files = vec![];
+        for e in fs::read_dir(&deps)
+            .chain_err(|| format!("couldn't read the directory {}", deps.display()))?
+        {
+            let e = e.chain_err(|| format!("couldn't read the directory {}", deps.display()))?;
+            files.push(e.file_name().to_string_lossy().into_owned());
+        }
+        // What went into this stage, for the SBOM
+        let mut cmd = command();
+        cmd.args(&["metadata", "--format-version", "1", "--filter-platform"]);
+        cmd.arg(cmode.target_arg());
+        cmd.arg("--manifest-path");
+        cmd.arg(td.join("Cargo.toml"));
+        if let Some(path) = rust_target_path.as_ref() {
+            cmd.env("RUST_TARGET_PATH", path);
+        }
+        if let Some(offline) = offline {
+            cmd.args(offline.args());
+        }
+        let components = sbom::components(&cmd.run_and_get_stdout(verbose)?)?;
+
+        let lock = util::read(&target_lockfile)?;
+        installed.push(Installed { stage: n, hash: *hash, files, lock, components });
+
+        // Update the fingerprint file after every stage so that the stages
+        // that were completed can be reused if a later stage fails
+        save(rustlib.parent(), cmode.triple(), meta, &installed)?;
+
+        // Keep Cargo's own reports
+        let cargo_timings = td.join("target").join("cargo-timings");
+        if timings.is_some() && cargo_timings.is_dir() {
+            let dst = rustlib.parent().join("cargo-timings").join(format!("stage-{}", n));
+            util::mkdir(&dst)?;
+            util::cp_r(&cargo_timings, &dst)?;
+        }
+
+        stage_timings.push(timings::Stage {
+            stage: n,
+            duration: stage_start.elapsed(),
+            crates: crate_timings,
+        });
+
+        if json {
+            event("xargo-sysroot-stage-finished", cmode.triple(), json!({ "stage": n }));
+        }
     }
 
-    // Create hash file
-    util::write(&rustlib.parent().join(".hash"), &hash.to_string())?;
+    let elapsed = start.elapsed();
+    shell.status(
+        "Finished",
+        &format!(
+            "sysroot for {} in {}.{:02}s",
+            cmode.triple(),
+            elapsed.as_secs(),
+            elapsed.subsec_millis() / 10
+        ),
+    );
+
+    Ok(stage_timings)
+}
 
-    Ok(())
+/// How the sysroot is built, as requested on the command line
+#[derive(Clone, Copy)]
+pub struct Options<'a> {
+    pub verbose: bool,
+    pub message_format: Option<&'a str>,
+    pub cargo_mode: XargoMode,
+    /// Build with `RUSTC_BOOTSTRAP=1` on a stable or beta toolchain
+    pub bootstrap: bool,
+    /// `--locked` or `--frozen`
+    pub locked: bool,
+    /// `--offline` or `--frozen`
+    pub offline: bool,
+    /// `--timings`, see `Args::timings`
+    pub timings: Option<&'a str>,
+    pub shell: &'a Shell,
+}
+
+/// Where the dependencies of the sysroot come from
+struct Sources {
+    lockfile: Lockfile,
+    offline: Option<Offline>,
+    /// The project's `.cargo/config` sections that apply to the sysroot build
+    stage_config: Option<Table>,
+}
+
+/// The lockfile used to build the sysroot
+enum Lockfile {
+    /// `lockfile` in `Xargo.toml`
+    Xargo(PathBuf),
+    /// The `Cargo.lock` that comes with the Rust source
+    Src(PathBuf),
+    /// None was available, so one is generated for every stage
+    Generated,
 }
 
-fn old_hash(cmode: &CompilationMode, home: &Home) -> Result<Option<u64>> {
+impl Lockfile {
+    fn new(src: &Src, xtoml: Option<&xargo::Toml>, base_path: &Path) -> Result<Lockfile> {
+        if let Some(lockfile) = xtoml.map(|xtoml| xtoml.lockfile()).unwrap_or(Ok(None))? {
+            let lockfile = base_path.join(lockfile);
+            if !lockfile.is_file() {
+                Err(format!(
+                    "Xargo.toml: `lockfile` {} doesn't exist",
+                    lockfile.display()
+                ))?
+            }
+
+            return Ok(Lockfile::Xargo(lockfile));
+        }
+
+        // rust-src comes with a lockfile for libstd. Use it.
+        let src_parent = src.path()
+            .parent()
+            .map(Path::to_path_buf)
+            .unwrap_or_else(|| src.path().join(".."));
+        let lockfile = src_parent.join("Cargo.lock");
+        if lockfile.is_file() {
+            Ok(Lockfile::Src(lockfile))
+        } else {
+            Ok(Lockfile::Generated)
+        }
+    }
+
+    fn hash<H>(&self, hasher: &mut H) -> Result<()>
+    where
+        H: Hasher,
+    {
+        match *self {
+            // The contents of the Rust source are covered by the `rustc`
+            // commit hash
+            Lockfile::Src(_) => "src".hash(hasher),
+            Lockfile::Xargo(ref path) => {
+                "xargo".hash(hasher);
+                util::read(path)?.hash(hasher);
+            }
+            Lockfile::Generated => "generated".hash(hasher),
+        }
+
+        Ok(())
+    }
+}
+
+/// `.cargo/config` sections that can be used when building the sysroot
+const STAGE_CONFIG_SECTIONS: &[&str] = &["http", "net", "patch", "registries", "registry", "source"];
+
+/// The sections used when `Xargo.toml` doesn't set `cargo-config`
+const DEFAULT_STAGE_CONFIG_SECTIONS: &[&str] = &["http", "net", "registries", "registry", "source"];
+
+/// Returns the `.cargo/config` of the sysroot build
+///
+/// These are the sections of the project's configuration listed in
+/// `cargo-config` in `Xargo.toml`, or `DEFAULT_STAGE_CONFIG_SECTIONS`
+fn stage_config(config: Option<&Config>, xtoml: Option<&xargo::Toml>) -> Result<Option<Table>> {
+    let sections = match xtoml {
+        Some(xtoml) => xtoml.cargo_config()?,
+        None => None,
+    };
+    let sections = sections.unwrap_or_else(|| {
+        DEFAULT_STAGE_CONFIG_SECTIONS.iter().map(|s| s.to_string()).collect()
+    });
+
+    for section in &sections {
+        if !STAGE_CONFIG_SECTIONS.contains(&&**section) {
+            Err(format!(
+                "Xargo.toml: `cargo-config` can't include `{}`; it can include: {}",
+                section,
+                STAGE_CONFIG_SECTIONS.join(", ")
+            ))?
+        }
+    }
+
+    let config = match config {
+        Some(config) => config,
+        None => return Ok(None),
+    };
+
+    let mut table = Table::new();
+    for section in sections {
+        if let Some(value) = config.section(&section) {
+            table.insert(section, value.clone());
+        }
+    }
+
+    // The sysroot build never publishes, and Cargo finds the credentials in
+    // `$CARGO_HOME` anyway. Keep them out of the stage directory, which is
+    // left behind with `XARGO_KEEP_TEMP`.
+    if let Some(&mut Value::Table(ref mut registry)) = table.get_mut("registry") {
+        strip_credentials(registry);
+    }
+    if let Some(&mut Value::Table(ref mut registries)) = table.get_mut("registries") {
+        for (_, registry) in registries.iter_mut() {
+            if let Value::Table(ref mut registry) = *registry {
+                strip_credentials(registry);
+            }
+        }
+    }
+
+    Ok(if table.is_empty() { None } else { Some(table) })
+}
+
+/// Removes the keys of a `[registry]` or `[registries.*]` table that hold or
+/// produce a token
+fn strip_credentials(registry: &mut Table) {
+    for key in &["token", "credential-provider", "global-credential-providers"] {
+        registry.remove(*key);
+    }
+}
+
+/// Returns the name, version and source of every package in `lockfile`
+fn lock_packages(lockfile: &Path) -> Result<BTreeSet<(String, String, String)>> {
+    let lock = util::parse(lockfile)?;
+    let field = |package: &Value, key: &str| {
+        package.get(key).and_then(|v| v.as_str()).unwrap_or("").to_owned()
+    };
+
+    Ok(lock.get("package")
+        .and_then(|p| p.as_array())
+        .map(|packages| {
+            packages
+                .iter()
+                .map(|p| (field(p, "name"), field(p, "version"), field(p, "source")))
+                .collect()
+        })
+        .unwrap_or_default())
+}
+
+/// Whether `message_format` asks Cargo for JSON messages
+fn is_json(message_format: Option<&str>) -> bool {
+    match message_format {
+        Some(format) => format.split(',').any(|f| f.trim().starts_with("json")),
+        None => false,
+    }
+}
+
+/// Prints an event about the sysroot build for tools that read Cargo's JSON
+/// messages
+///
+/// Their `reason` is never one of Cargo's so these tools can tell them apart
+/// from the messages about the project.
+fn event(reason: &str, triple: &str, mut fields: JsonValue) {
+    fields["reason"] = json!(reason);
+    fields["target"] = json!(triple);
+    println!("{}", fields);
+}
+
+/// A stage whose artifacts have been installed into the sysroot
+struct Installed {
+    /// The key of the stage in `Xargo.toml`
+    stage: i64,
+    hash: u64,
+    files: Vec<String>,
+    /// The `Cargo.lock` the stage was built with
+    lock: String,
+    /// The SBOM components of the crates the stage was built from
+    components: Vec<JsonValue>,
+}
+
+/// Reads the `.fingerprint` file of a sysroot
+///
+/// A missing or unreadable fingerprint file means that nothing can be reused
+fn read_installed(path: &Path) -> Result<Vec<Installed>> {
+    if !path.exists() {
+        return Ok(vec![]);
+    }
+
+    let json = match serde_json::from_str::<JsonValue>(&util::read(path)?) {
+        Ok(json) => json,
+        Err(_) => return Ok(vec![]),
+    };
+
+    let mut installed = vec![];
+    for stage in json.as_array().map(|a| &a[..]).unwrap_or(&[]) {
+        let key = stage.get("stage").and_then(|s| s.as_i64());
+        let hash = stage.get("hash").and_then(|h| h.as_u64());
+        let files = stage.get("files").and_then(|f| f.as_array()).map(|files| {
+            files
+                .iter()
+                .filter_map(|f| f.as_str().map(|f| f.to_owned()))
+                .collect()
+        });
+
+        let lock = stage.get("lock").and_then(|l| l.as_str()).unwrap_or("").to_owned();
+        let components = stage
+            .get("components")
+            .and_then(|c| c.as_array())
+            .cloned()
+            .unwrap_or_default();
+
+        match (key, hash, files) {
+            (Some(key), Some(hash), Some(files)) => installed.push(Installed {
+                stage: key,
+                hash,
+                files,
+                lock,
+                components,
+            }),
+            _ => return Ok(vec![]),
+        }
+    }
+
+    Ok(installed)
+}
+
+fn write_installed(path: &Path, installed: &[Installed]) -> Result<()> {
+    let json = installed
+        .iter()
+        .map(|stage| {
+            json!({
+                "stage": stage.stage,
+                "hash": stage.hash,
+                "files": stage.files,
+                "lock": stage.lock,
+                "components": stage.components,
+            })
+        })
+        .collect::<Vec<_>>();
+
+    util::write(path, &JsonValue::Array(json).to_string())
+}
+
+/// Writes the fingerprint file, the lockfile and the SBOM of the sysroot in
+/// `dir`, the directory of the target
+fn save(dir: &Path, triple: &str, meta: &VersionMeta, installed: &[Installed]) -> Result<()> {
+    write_installed(&dir.join(".fingerprint"), installed)?;
+    write_lockfile(&dir.join("Cargo.lock"), installed)?;
+    for stage in installed {
+        util::write(&stage_lockfile(dir, stage.stage), &stage.lock)?;
+    }
+    sbom::write(
+        &dir.join("sbom.cdx.json"),
+        triple,
+        meta,
+        &installed.iter().map(|stage| &stage.components[..]).collect::<Vec<_>>(),
+    )
+}
+
+/// The lockfile a stage was built with, as kept in `dir`, the directory of the
+/// target
+fn stage_lockfile(dir: &Path, stage: i64) -> PathBuf {
+    dir.join(format!("Cargo.lock.stage-{}", stage))
+}
+
+/// Writes the packages of the lockfiles of all the `installed` stages to a
+/// single lockfile, which can be set as `lockfile` in `Xargo.toml`
+///
+/// The `sysroot` package of each stage is merged into one that depends on the
+/// crates of every stage.
+fn write_lockfile(path: &Path, installed: &[Installed]) -> Result<()> {
+    let field = |package: &Value, key: &str| {
+        package.get(key).and_then(|v| v.as_str()).unwrap_or("").to_owned()
+    };
+
+    let mut version = None;
+    let mut packages = BTreeMap::new();
+    for stage in installed {
+        let lock = match stage.lock.parse::<Value>() {
+            Ok(lock) => lock,
+            Err(_) => continue,
+        };
+
+        if let Some(v) = lock.get("version").and_then(|v| v.as_integer()) {
+            version = Some(version.map_or(v, |version: i64| version.max(v)));
+        }
+
+        for package in lock.get("package").and_then(|p| p.as_array()).into_iter().flatten() {
+            let key = (field(package, "name"), field(package, "version"), field(package, "source"));
+            let is_root = key.0 == "sysroot" && key.2.is_empty();
+            let merged = packages.entry(key).or_insert_with(|| package.clone());
+
+            if is_root {
+                let deps = package.get("dependencies").and_then(|d| d.as_array());
+                if let Value::Table(ref mut root) = *merged {
+                    let merged_deps = root
+                        .entry("dependencies")
+                        .or_insert_with(|| Value::Array(vec![]));
+                    if let Value::Array(ref mut merged_deps) = *merged_deps {
+                        for dep in deps.into_iter().flatten() {
+                            if !merged_deps.contains(dep) {
+                                merged_deps.push(dep.clone());
+                            }
+                        }
+                        merged_deps.sort_by(|a, b| a.as_str().cmp(&b.as_str()));
+                    }
+                }
+            }
+        }
+    }
+
+    let mut lock = Table::new();
+    if let Some(version) = version {
+        lock.insert("version".to_owned(), Value::Integer(version));
+    }
+    lock.insert("package".to_owned(), Value::Array(packages.into_values().collect()));
+
+    let mut contents = "# The packages Xargo used to build this sysroot\n".to_owned();
+    contents.push_str(
+        &toml::to_string(&Value::Table(lock)).chain_err(|| "couldn't serialize the sysroot lockfile")?,
+    );
+
+    util::write(path, &contents)
+}
+
+fn old_hashes(cmode: &CompilationMode, home: &Home) -> Result<Vec<u64>> {
     // FIXME this should be `lock_ro`
     let lock = home.lock_rw(cmode.triple())?;
-    let hfile = lock.parent().join(".hash");
 
-    if hfile.exists() {
-        Ok(util::read(&hfile)?.parse().ok())
-    } else {
-        Ok(None)
-    }
+    Ok(read_installed(&lock.parent().join(".fingerprint"))?
+        .into_iter()
+        .map(|stage| stage.hash)
+        .collect())
 }
 
-/// Computes the hash of the would-be target sysroot
+/// Returns how many of the leading `installed` stages can be reused as they
+/// are
+fn fresh_stages(installed: &[Installed], hashes: &[u64]) -> usize {
+    installed
+        .iter()
+        .zip(hashes)
+        .take_while(|&(stage, hash)| stage.hash == *hash)
+        .count()
+}
+
+/// Computes the fingerprints of the stages of the would-be target sysroot
 ///
-/// This information is used to compute the hash
+/// The fingerprint of each stage covers the dependencies in `Xargo.toml` that
+/// belong to that stage and is chained on the fingerprint of the stage before
+/// it. The first stage is chained on a fingerprint of
 ///
-/// - Dependencies in `Xargo.toml` for a specific target
 /// - RUSTFLAGS / build.rustflags / target.*.rustflags
 /// - The target specification file, is any
 /// - `[profile.release]` in `Cargo.toml`
 /// - `rustc` commit hash
+/// - Whether the sysroot is built with `cargo build` or `cargo check`
+/// - Whether the sysroot is built with `RUSTC_BOOTSTRAP=1`
+/// - The lockfile, if given in `Xargo.toml`
+/// - The `[patch]` section of `.cargo/config`, if used
 fn hash(
     cmode: &CompilationMode,
     blueprint: &Blueprint,
     rustflags: &Rustflags,
     ctoml: &Option<cargo::Toml>,
     meta: &VersionMeta,
-) -> Result<u64> {
+    sources: &Sources,
+    options: &Options,
+) -> Result<Vec<u64>> {
     let mut hasher = DefaultHasher::new();
 
-    blueprint.hash(&mut hasher);
+    options.cargo_mode.hash(&mut hasher);
+
+    options.bootstrap.hash(&mut hasher);
+
+    sources.lockfile.hash(&mut hasher)?;
+
+    // The other sections only affect where the crates come from
+    if let Some(patch) = sources.stage_config.as_ref().and_then(|c| c.get("patch")) {
+        patch.to_string().hash(&mut hasher);
+    }
 
     rustflags.hash(&mut hasher);
 
@@ -269,9 +904,100 @@ fn hash(
         hash.hash(&mut hasher);
     }
 
-    Ok(hasher.finish())
+    let mut prev = hasher.finish();
+    Ok(blueprint
+        .stages
+        .iter()
+        .map(|(n, stage)| {
+            let mut hasher = DefaultHasher::new();
+            prev.hash(&mut hasher);
+            // The stage's exports are named after its key
+            n.hash(&mut hasher);
+            stage.hash(&mut hasher);
+            prev = hasher.finish();
+            prev
+        })
+        .collect())
+}
+
+fn ctoml(root: &Root, cargo_mode: XargoMode) -> Result<Option<cargo::Toml>> {
+    match cargo_mode {
+        XargoMode::Build => Ok(Some(cargo::toml(root)?)),
+        XargoMode::Check => {
+            if root.path().join("Cargo.toml").exists() {
+                Ok(Some(cargo::toml(root)?))
+            } else {
+                Ok(None)
+            }
+        }
+    }
 }
 
+/// Checks whether `rustc`'s own sysroot already has what Xargo would build
+///
+/// That's the case when the target's standard crates are installed (e.g. with
+/// `rustup target add`) and the sysroot isn't customized in any way: no
+/// `Xargo.toml` dependencies or patches, no RUSTFLAGS and no
+/// `[profile.release]` settings. This can be opted out of by setting the
+/// `XARGO_FORCE_BUILD` env variable.
+pub fn prebuilt(
+    cmode: &CompilationMode,
+    root: &Root,
+    rustflags: &Rustflags,
+    sysroot: &Sysroot,
+    cargo_mode: XargoMode,
+) -> Result<bool> {
+    if env::var_os("XARGO_FORCE_BUILD").is_some() {
+        return Ok(false);
+    }
+
+    match *cmode {
+        CompilationMode::Cross(Target::Builtin { .. }) | CompilationMode::Native(_) => {}
+        _ => return Ok(false),
+    }
+
+    let lib = sysroot
+        .path()
+        .join("lib")
+        .join("rustlib")
+        .join(cmode.triple())
+        .join("lib");
+    let has_core = lib.read_dir()
+        .map(|entries| {
+            entries.filter_map(|e| e.ok()).any(|e| {
+                let name = e.file_name();
+                let name = name.to_string_lossy();
+                name.starts_with("libcore-") && name.ends_with(".rlib")
+            })
+        })
+        .unwrap_or(false);
+    if !has_core {
+        return Ok(false);
+    }
+
+    let (_, xtoml) = xargo::toml(root)?;
+    if let Some(xtoml) = xtoml {
+        if xtoml.dependencies().is_some()
+            || xtoml.target_dependencies(cmode.triple()).is_some()
+            || xtoml.patch().is_some()
+        {
+            return Ok(false);
+        }
+    }
+
+    if let Some(ctoml) = ctoml(root, cargo_mode)? {
+        if ctoml.profile().map(|p| !p.is_empty()).unwrap_or(false) {
+            return Ok(false);
+        }
+    }
+
+    Ok(rustflags.is_empty())
+}
+
+/// Builds the sysroot, unless it's fresh
+///
+/// Returns whether its `compiler_builtins` provides `memcpy` and friends, i.e.
+/// whether it was built with the `mem` feature
 pub fn update(
     cmode: &CompilationMode,
     home: &Home,
@@ -280,20 +1006,12 @@ pub fn update(
     meta: &VersionMeta,
     src: &Src,
     sysroot: &Sysroot,
-    verbose: bool,
-    message_format: Option<&str>,
-    cargo_mode: XargoMode,
-) -> Result<()> {
-    let ctoml = match cargo_mode {
-        XargoMode::Build => Some(cargo::toml(root)?),
-        XargoMode::Check => {
-            if root.path().join("Cargo.toml").exists() {
-                Some(cargo::toml(root)?)
-            } else {
-                None
-            }
-        }
-    };
+    config: Option<&Config>,
+    options: &Options,
+) -> Result<bool> {
+    let Options { verbose, message_format, cargo_mode, shell, timings, .. } = *options;
+    let start = Instant::now();
+    let ctoml = ctoml(root, cargo_mode)?;
 
     let (xtoml_parent, xtoml) = xargo::toml(root)?;
 
@@ -303,12 +1021,38 @@ pub fn update(
     // root path.
     let base_path: &Path = xtoml_parent.unwrap_or_else(|| root.path());
 
-    let blueprint = Blueprint::from(xtoml.as_ref(), cmode.triple(), &base_path, &src)?;
+    let blueprint = Blueprint::from(xtoml.as_ref(), cmode, &base_path, &src, rustflags, meta, verbose)?;
+    let builtins_mem = blueprint.builtins_mem();
+    let sources = Sources {
+        lockfile: Lockfile::new(src, xtoml.as_ref(), base_path)?,
+        offline: Offline::new(xtoml.as_ref(), base_path, options.offline)?,
+        stage_config: stage_config(config, xtoml.as_ref())?,
+    };
+
+    let mut hashes = hash(cmode, &blueprint, rustflags, &ctoml, meta, &sources, options)?;
+    let old_hashes = old_hashes(cmode, home)?;
+
+    if cargo_mode == XargoMode::Check {
+        // A stage built by `cargo build` contains everything `cargo check`
+        // needs, so reuse it. The reverse is not true: `cargo check` only
+        // produces metadata, which can't be linked.
+        let build_options = Options { cargo_mode: XargoMode::Build, ..*options };
+        let build_hashes =
+            hash(cmode, &blueprint, rustflags, &ctoml, meta, &sources, &build_options)?;
+        for (hash, (old, build)) in hashes.iter_mut().zip(old_hashes.iter().zip(&build_hashes)) {
+            if old != build {
+                break;
+            }
+
+            *hash = *build;
+        }
+    }
 
-    let hash = hash(cmode, &blueprint, rustflags, &ctoml, meta)?;
+    let fingerprint = start.elapsed();
 
-    if old_hash(cmode, home)? != Some(hash) {
-        build(
+    let mut stages = vec![];
+    if old_hashes != hashes {
+        let result = build(
             cmode,
             blueprint,
             &ctoml,
@@ -316,18 +1060,58 @@ pub fn update(
             rustflags,
             src,
             sysroot,
-            hash,
-            verbose,
-            message_format,
-            cargo_mode,
-        )?;
+            &hashes,
+            meta,
+            &sources,
+            options,
+        );
+
+        if let Err(ref e) = result {
+            if is_json(message_format) {
+                event(
+                    "xargo-sysroot-build-failed",
+                    cmode.triple(),
+                    json!({ "error": e.to_string() }),
+                );
+            }
+        }
+        stages = result?;
+    } else {
+        shell.status("Fresh", &format!("sysroot for {}", cmode.triple()));
+
+        if is_json(message_format) {
+            event("xargo-sysroot-fresh", cmode.triple(), json!({}));
+        }
     }
 
+    let start = Instant::now();
     // copy host artifacts into the sysroot, if necessary
-    if cmode.is_native() {
-        return Ok(());
+    if !cmode.is_native() {
+        copy_host(home, meta, sysroot)?;
     }
 
+    let timings_ = Timings {
+        fingerprint,
+        stages,
+        host: start.elapsed(),
+    };
+
+    // Keep the timings of the last build
+    if !timings_.stages.is_empty() {
+        let lock = home.lock_rw(cmode.triple())?;
+        timings_.write(&lock.parent().join("timings.json"), cmode.triple())?;
+    }
+
+    if timings.is_some() {
+        timings_.print(shell, cmode.triple());
+    }
+
+    Ok(builtins_mem)
+}
+
+/// Copies the host artifacts of `rustc`'s sysroot into Xargo's, unless they
+/// are already there
+fn copy_host(home: &Home, meta: &VersionMeta, sysroot: &Sysroot) -> Result<()> {
     let lock = home.lock_rw(&meta.host)?;
     let hfile = lock.parent().join(".hash");
 
@@ -375,7 +1159,7 @@ pub struct Stage {
 
 /// Which mode to invoke `cargo` in when building the sysroot
 /// Can be either `cargo build` or `cargo check`
-#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+#[derive(Copy, Clone, Debug, Eq, Hash, PartialEq)]
 pub enum XargoMode {
     Build,
     Check,
@@ -443,7 +1227,17 @@ impl Blueprint {
         Ok(())
     }
 
-    fn from(toml: Option<&xargo::Toml>, target: &str, base_path: &Path, src: &Src) -> Result<Self> {
+    fn from(
+        toml: Option<&xargo::Toml>,
+        cmode: &CompilationMode,
+        base_path: &Path,
+        src: &Src,
+        rustflags: &Rustflags,
+        meta: &VersionMeta,
+        verbose: bool,
+    ) -> Result<Self> {
+        let target = cmode.triple();
+
         fn make_path_absolute<F, R>(
             crate_spec: &mut Table,
             base_path: &Path,
@@ -534,30 +1328,7 @@ impl Blueprint {
                     target
                 ))?
             },
-            (None, None) => {
-                // If no dependencies were listed, we assume `core` and `compiler_builtins` as the
-                // dependencies
-                let mut t = Map::new();
-                let mut core = Map::new();
-                core.insert("stage".to_owned(), Value::Integer(0));
-                t.insert("core".to_owned(), Value::Table(core));
-                let mut cb = Map::new();
-                cb.insert(
-                    "features".to_owned(),
-                    Value::Array(vec![Value::String("mem".to_owned())]),
-                );
-                // reference compiler-builtins with `version = "*"`,
-                // the corresponding version of compiler_builtins matching the used std
-                // is selected because of the copied `Cargo.lock`-file from std.
-                cb.insert("version".to_owned(),
-                    Value::String("*".to_owned()));
-                cb.insert("stage".to_owned(), Value::Integer(1));
-                t.insert(
-                    "compiler_builtins".to_owned(),
-                    Value::Table(cb),
-                );
-                t
-            }
+            (None, None) => Blueprint::default_dependencies(cmode, src, rustflags, meta, verbose)?,
         };
 
         let mut blueprint = Blueprint::new();
@@ -599,6 +1370,74 @@ impl Blueprint {
         Ok(blueprint)
     }
 
+    /// The dependencies to use when none were listed in `Xargo.toml`
+    ///
+    /// These depend on the properties of the target, see `rustc --print cfg`
+    fn default_dependencies(
+        cmode: &CompilationMode,
+        src: &Src,
+        rustflags: &Rustflags,
+        meta: &VersionMeta,
+        verbose: bool,
+    ) -> Result<Table> {
+        fn features(names: &[&str]) -> Value {
+            Value::Array(names.iter().map(|n| Value::String(n.to_string())).collect())
+        }
+
+        let cfg = rustc::cfg(cmode, rustflags, meta, verbose)?;
+
+        // Platforms with an OS provide `memcpy` & co, elsewhere they have to
+        // come from `compiler_builtins`
+        let mem = cfg.has("target_os", "none") || cfg.has("target_os", "uefi");
+        // Leave `alloc` out on the targets that are too constrained to have
+        // pointer sized atomics, and when its `compiler_builtins` can't be
+        // given the `mem` feature
+        let alloc = (cfg.has("target_has_atomic", "ptr")
+            || cfg.has("target_has_atomic_load_store", "ptr"))
+            && (!mem || util::read(&src.path().join("alloc").join("Cargo.toml"))
+                .map(|toml| toml.contains("compiler-builtins-mem"))
+                .unwrap_or(false));
+
+        let mut t = Map::new();
+        if alloc {
+            // `alloc` pulls in `core` and `compiler_builtins`. Building them
+            // all in the same stage makes sure they are only built once.
+            let mut alloc = Map::new();
+            if mem {
+                alloc.insert("features".to_owned(), features(&["compiler-builtins-mem"]));
+            }
+            alloc.insert("stage".to_owned(), Value::Integer(0));
+            t.insert("alloc".to_owned(), Value::Table(alloc));
+
+            // There's no unwinder to build on these targets
+            if cfg.has("panic", "abort") && src.path().join("panic_abort").exists() {
+                let mut panic_abort = Map::new();
+                panic_abort.insert("stage".to_owned(), Value::Integer(0));
+                t.insert("panic_abort".to_owned(), Value::Table(panic_abort));
+            }
+        } else {
+            let mut core = Map::new();
+            core.insert("stage".to_owned(), Value::Integer(0));
+            t.insert("core".to_owned(), Value::Table(core));
+            let mut cb = Map::new();
+            if mem {
+                cb.insert("features".to_owned(), features(&["mem"]));
+            }
+            // reference compiler-builtins with `version = "*"`,
+            // the corresponding version of compiler_builtins matching the used std
+            // is selected because of the copied `Cargo.lock`-file from std.
+            cb.insert("version".to_owned(),
+                Value::String("*".to_owned()));
+            cb.insert("stage".to_owned(), Value::Integer(1));
+            t.insert(
+                "compiler_builtins".to_owned(),
+                Value::Table(cb),
+            );
+        }
+
+        Ok(t)
+    }
+
     fn push(&mut self, stage: i64, krate: String, toml: Table, patch: &Table) {
         let stage = self.stages.entry(stage).or_insert_with(|| Stage {
             crates: vec![],
@@ -610,15 +1449,31 @@ impl Blueprint {
         stage.crates.push(krate);
     }
 
+    /// Whether `compiler_builtins` is built with the `mem` feature, directly
+    /// or through the `compiler-builtins-mem` feature of `alloc` or `std`
+    fn builtins_mem(&self) -> bool {
+        self.stages
+            .values()
+            .flat_map(|stage| stage.dependencies.iter())
+            .any(|(krate, dep)| {
+                let features = dep.get("features").and_then(|f| f.as_array());
+                features.into_iter().flatten().any(|f| {
+                    f.as_str() == Some("compiler-builtins-mem")
+                        || (krate == "compiler_builtins" && f.as_str() == Some("mem"))
+                })
+            })
+    }
+
+}
+
+impl Stage {
     fn hash<H>(&self, hasher: &mut H)
     where
         H: Hasher,
     {
-        for stage in self.stages.values() {
-            for (k, v) in stage.dependencies.iter() {
-                k.hash(hasher);
-                v.to_string().hash(hasher);
-            }
+        for (k, v) in self.dependencies.iter() {
+            k.hash(hasher);
+            v.to_string().hash(hasher);
         }
     }
 }
@@ -0,0 +1,88 @@
+use std::path::Path;
+use std::time::Duration;
+
+use serde_json;
+
+use errors::*;
+use shell::Shell;
+use util;
+
+/// How long building a sysroot took
+pub struct Timings {
+    /// Computing and comparing the fingerprints of the stages
+    pub fingerprint: Duration,
+    /// The stages that were built
+    pub stages: Vec<Stage>,
+    /// Copying the host artifacts into the sysroot
+    pub host: Duration,
+}
+
+/// How long building a stage took
+pub struct Stage {
+    pub stage: i64,
+    pub duration: Duration,
+    /// Each `cargo build -p $crate` invocation
+    pub crates: Vec<(String, Duration)>,
+}
+
+fn secs(duration: Duration) -> String {
+    format!("{:.2}s", duration.as_secs_f64())
+}
+
+impl Timings {
+    fn total(&self) -> Duration {
+        self.stages.iter().map(|s| s.duration).sum::<Duration>() + self.fingerprint + self.host
+    }
+
+    /// Writes a JSON report of the timings of the sysroot of `triple`
+    pub fn write(&self, path: &Path, triple: &str) -> Result<()> {
+        let stages = self.stages
+            .iter()
+            .map(|stage| {
+                json!({
+                    "stage": stage.stage,
+                    "duration": stage.duration.as_secs_f64(),
+                    "crates": stage.crates
+                        .iter()
+                        .map(|&(ref name, duration)| {
+                            json!({ "name": name, "duration": duration.as_secs_f64() })
+                        })
+                        .collect::<Vec<_>>(),
+                })
+            })
+            .collect::<Vec<_>>();
+
+        let report = json!({
+            "target": triple,
+            "fingerprint": self.fingerprint.as_secs_f64(),
+            "stages": stages,
+            "host-copy": self.host.as_secs_f64(),
+            "total": self.total().as_secs_f64(),
+        });
+
+        let json = serde_json::to_string_pretty(&report)
+            .chain_err(|| "couldn't serialize the timings")?;
+        util::write(path, &json)
+    }
+
+    /// Prints a summary of the timings
+    pub fn print(&self, shell: &Shell, triple: &str) {
+        shell.status(
+            "Timings",
+            &format!("sysroot for {}: {} in total", triple, secs(self.total())),
+        );
+        shell.status("", &format!("fingerprint check: {}", secs(self.fingerprint)));
+        for stage in &self.stages {
+            let crates = stage.crates
+                .iter()
+                .map(|&(ref name, duration)| format!("{} {}", name, secs(duration)))
+                .collect::<Vec<_>>()
+                .join(", ");
+            shell.status(
+                "",
+                &format!("stage {}: {} ({})", stage.stage, secs(stage.duration), crates),
+            );
+        }
+        shell.status("", &format!("host copy: {}", secs(self.host)));
+    }
+}
@@ -55,6 +55,29 @@ pub fn cp_r(src: &Path, dst: &Path) -> Result<()> {
     Ok(())
 }
 
+/// Levenshtein distance between `a` and `b`
+pub fn edit_distance(a: &str, b: &str) -> usize {
+    let b = b.chars().collect::<Vec<_>>();
+    let mut row = (0..b.len() + 1).collect::<Vec<_>>();
+
+    for (i, ca) in a.chars().enumerate() {
+        let mut diag = row[0];
+        row[0] = i + 1;
+
+        for (j, &cb) in b.iter().enumerate() {
+            let above = row[j + 1];
+            row[j + 1] = if ca == cb {
+                diag
+            } else {
+                1 + diag.min(above).min(row[j])
+            };
+            diag = above;
+        }
+    }
+
+    row[b.len()]
+}
+
 pub fn mkdir(path: &Path) -> Result<()> {
     fs::create_dir(path).chain_err(|| format!("couldn't create directory {}", path.display()))
 }
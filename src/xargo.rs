@@ -12,7 +12,8 @@ use cli::Args;
 use errors::*;
 use extensions::CommandExt;
 use flock::{FileLock, Filesystem};
-use {cargo, util};
+use rustc::Target;
+use {cargo, diagnostics, rustc, util};
 
 pub fn run(
     args: &Args,
@@ -21,12 +22,13 @@ pub fn run(
     home: &Home,
     meta: &VersionMeta,
     config: Option<&Config>,
-    verbose: bool,
+    builtins_mem: bool,
 ) -> Result<ExitStatus> {
+    let verbose = args.verbose();
     let mut cmd = cargo::command();
     cmd.args(args.all());
 
-    if args.subcommand() == Some(Subcommand::Doc) {
+    if args.subcommand() == Some(&Subcommand::Doc) {
         cmd.env(
             "CARGO_ENCODED_RUSTDOCFLAGS",
             cargo::rustdocflags(config, cmode.triple())?.encode(home),
@@ -36,12 +38,24 @@ pub fn run(
     if verbose {
         writeln!(io::stderr(), "+ RUSTFLAGS={}", rustflags).ok();
     }
-    cmd.env("CARGO_ENCODED_RUSTFLAGS", rustflags.encode(home));
+    cmd.env("CARGO_ENCODED_RUSTFLAGS", rustflags.clone().encode(home));
+
+    // The specification file may live in a directory `rustc` doesn't search
+    if let CompilationMode::Cross(Target::Custom { ref json, .. }) = *cmode {
+        cmd.env("RUST_TARGET_PATH", rustc::rust_target_path(json)?);
+    }
 
     let locks = (home.lock_ro(&meta.host), home.lock_ro(cmode.triple()));
 
     let status = cmd.run_and_get_status(verbose)?;
 
+    // `build` and `rustc` link the project against the sysroot
+    let linked = args.subcommand() == Some(&Subcommand::Build)
+        || args.subcommand() == Some(&Subcommand::Rustc);
+    if linked && !status.success() && !builtins_mem {
+        diagnostics::missing_mem(cmode, &rustflags, meta, verbose);
+    }
+
     mem::drop(locks);
 
     Ok(status)
@@ -119,6 +133,74 @@ impl Toml {
     pub fn patch(&self) -> Option<&Value> {
         self.table.get("patch")
     }
+
+    /// Returns the `allow-bootstrap` part of `Xargo.toml`
+    pub fn allow_bootstrap(&self) -> Result<bool> {
+        match self.table.get("allow-bootstrap") {
+            None => Ok(false),
+            Some(v) => Ok(v.as_bool()
+                .ok_or("Xargo.toml: `allow-bootstrap` must be a boolean")?),
+        }
+    }
+
+    /// Returns the `offline` part of `Xargo.toml`
+    pub fn offline(&self) -> Option<&Value> {
+        self.table.get("offline")
+    }
+
+    /// Returns the `cargo-config` part of `Xargo.toml`
+    pub fn cargo_config(&self) -> Result<Option<Vec<String>>> {
+        let error = || "Xargo.toml: `cargo-config` must be an array of strings";
+
+        match self.table.get("cargo-config") {
+            None => Ok(None),
+            Some(Value::Array(array)) => array
+                .iter()
+                .map(|v| v.as_str().map(|s| s.to_owned()).ok_or_else(|| error().into()))
+                .collect::<Result<_>>()
+                .map(Some),
+            Some(_) => Err(error())?,
+        }
+    }
+
+    /// Returns the `lockfile` part of `Xargo.toml`
+    pub fn lockfile(&self) -> Result<Option<PathBuf>> {
+        match self.table.get("lockfile") {
+            None => Ok(None),
+            Some(v) => Ok(Some(PathBuf::from(
+                v.as_str().ok_or("Xargo.toml: `lockfile` must be a string")?,
+            ))),
+        }
+    }
+
+    /// Returns the `target-path` part of `Xargo.toml`
+    pub fn target_path(&self) -> Result<Vec<PathBuf>> {
+        let error = || "Xargo.toml: `target-path` must be a string or an array of strings";
+
+        match self.table.get("target-path") {
+            None => Ok(vec![]),
+            Some(Value::String(s)) => Ok(vec![PathBuf::from(s)]),
+            Some(Value::Array(array)) => array
+                .iter()
+                .map(|v| v.as_str().map(PathBuf::from).ok_or_else(|| error().into()))
+                .collect(),
+            Some(_) => Err(error())?,
+        }
+    }
+}
+
+/// Whether the user allows building the sysroot with `RUSTC_BOOTSTRAP=1`,
+/// either through the `XARGO_ALLOW_BOOTSTRAP` env variable or
+/// `allow-bootstrap = true` in `Xargo.toml`
+pub fn allow_bootstrap(xtoml: Option<&Toml>) -> Result<bool> {
+    if env::var_os("XARGO_ALLOW_BOOTSTRAP").is_some() {
+        return Ok(true);
+    }
+
+    match xtoml {
+        Some(xtoml) => xtoml.allow_bootstrap(),
+        None => Ok(false),
+    }
 }
 
 /// Returns the closest directory containing a 'Xargo.toml' and the parsed
@@ -9,6 +9,7 @@ extern crate rustc_version;
 extern crate tempdir;
 extern crate dirs;
 
+use std::collections::BTreeSet;
 use std::fs::OpenOptions;
 use std::io::Write;
 use std::path::{Path, PathBuf};
@@ -76,6 +77,50 @@ fn exists(krate: &str, target: &str) -> Result<bool> {
     Ok(false)
 }
 
+/// Copies the directory `src` into `dst`, recursively
+fn cp_r(src: &Path, dst: &Path) -> Result<()> {
+    mkdir(dst)?;
+    for e in fs::read_dir(src).chain_err(|| format!("couldn't read the directory {}", src.display()))? {
+        let e = e.chain_err(|| format!("couldn't read the directory {}", src.display()))?;
+        let (src, dst) = (e.path(), dst.join(e.file_name()));
+        if src.is_dir() {
+            cp_r(&src, &dst)?;
+        } else {
+            fs::copy(&src, &dst).chain_err(|| format!("couldn't copy {}", src.display()))?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Creates a vendor directory in `dir` with a single crate,
+/// `xargo-vendored`, laid out as `cargo vendor` would
+fn vendor(dir: &Path) -> Result<()> {
+    let krate = dir.join("xargo-vendored");
+    mkdir(dir)?;
+    mkdir(&krate)?;
+    mkdir(&krate.join("src"))?;
+    write(
+        &krate.join("Cargo.toml"),
+        false,
+        "[package]\nname = \"xargo-vendored\"\nversion = \"0.1.0\"\n",
+    )?;
+    write(&krate.join("src/lib.rs"), false, "#![no_std]")?;
+    write(&krate.join(".cargo-checksum.json"), false, "{\"files\":{},\"package\":null}")
+}
+
+/// Returns the `rust-src` component of the toolchain, i.e. the directory
+/// containing `library` and `Cargo.lock`
+fn rust_src() -> Result<PathBuf> {
+    let out = Command::new("rustc")
+        .args(&["--print", "sysroot"])
+        .output()
+        .chain_err(|| "couldn't execute `rustc`")?;
+    let sysroot = String::from_utf8_lossy(&out.stdout);
+
+    Ok(Path::new(sysroot.trim()).join("lib/rustlib/src/rust"))
+}
+
 fn host() -> String {
     rustc_version::version_meta().unwrap().host
 }
@@ -89,7 +134,7 @@ fn mkdir(path: &Path) -> Result<()> {
 fn sysroot_was_built(stderr: &str, target: &str) -> bool {
     stderr.lines().filter(|l| l.starts_with("+")).any(|l| {
         l.contains("cargo") && l.contains("build") && l.contains("--target") && l.contains(target)
-            && l.contains("-p") && l.contains("core")
+            && l.contains("-p") && (l.contains("core") || l.contains("alloc"))
     })
 }
 
@@ -356,6 +401,8 @@ impl HProject {
     /// Calls `xargo build` and collects STDERR
     fn build_and_get_stderr(&self) -> Result<String> {
         let mut cmd = xargo()?;
+        // The host always has a prebuilt sysroot; make sure Xargo builds one
+        cmd.env("XARGO_FORCE_BUILD", "1");
         cmd.arg("build");
 
         cmd.arg("-v")
@@ -371,6 +418,7 @@ impl HProject {
     /// Runs `xargo-check` with the specified subcommand
     fn xargo_check_subcommand(&self, subcommand: Option<&str>, target: Option<&str>) -> Result<String> {
         let mut cmd = xargo_check()?;
+        cmd.env("XARGO_FORCE_BUILD", "1");
         if let Some(subcommand) = subcommand {
             cmd.arg(subcommand);
         }
@@ -406,6 +454,209 @@ fn simple() {
     run!()
 }
 
+/// Check that `+toolchain` applies to both the sysroot build and the final
+/// `cargo` invocation
+#[test]
+fn toolchain() {
+    fn run() -> Result<()> {
+        const TARGET: &'static str = "thumbv6m-toolchain-eabi";
+
+        let toolchain = env::var("RUSTUP_TOOLCHAIN").unwrap_or_else(|_| "nightly".to_owned());
+        let project = Project::new(TARGET)?;
+
+        let stderr = xargo()?
+            .arg(format!("+{}", toolchain))
+            .args(&["build", "-v", "--target", TARGET])
+            .env_remove("RUSTUP_TOOLCHAIN")
+            .current_dir(project.td.path())
+            .run_and_get_stderr()?;
+        assert!(stderr.contains(&format!("+ RUSTUP_TOOLCHAIN={}", toolchain)));
+        assert!(sysroot_was_built(&stderr, TARGET));
+
+        // The sysroot was built by the requested `rustc`, and the project
+        // could only be built against it by the same toolchain
+        let version = Command::new("rustc")
+            .arg(format!("+{}", toolchain))
+            .arg("--version")
+            .output()
+            .chain_err(|| "couldn't execute `rustc`")?;
+        let version = String::from_utf8_lossy(&version.stdout);
+        let sbom = home()?.join("lib/rustlib").join(TARGET).join("sbom.cdx.json");
+        let contents = fs::read_to_string(&sbom)
+            .chain_err(|| format!("couldn't read {}", sbom.display()))?;
+        assert!(contents.contains(version.trim()));
+
+        Ok(())
+    }
+
+    run!()
+}
+
+/// Check that a `rust-toolchain` file in the project also applies to the
+/// sysroot build, which runs in another directory
+#[test]
+fn toolchain_file() {
+    fn run() -> Result<()> {
+        const TARGET: &'static str = "thumbv6m-toolchain_file-eabi";
+
+        let toolchain = env::var("RUSTUP_TOOLCHAIN").unwrap_or_else(|_| "nightly".to_owned());
+        let project = Project::new(TARGET)?;
+        write(&project.td.path().join("rust-toolchain"), false, &toolchain)?;
+
+        let out = Command::new("rustc")
+            .arg(format!("+{}", toolchain))
+            .args(&["--print", "sysroot"])
+            .output()
+            .chain_err(|| "couldn't execute `rustc`")?;
+        let sysroot = PathBuf::from(String::from_utf8_lossy(&out.stdout).trim());
+
+        let stderr = xargo()?
+            .args(&["build", "-v", "--target", TARGET])
+            .env_remove("RUSTUP_TOOLCHAIN")
+            .env_remove("CARGO")
+            .env_remove("RUSTC")
+            .current_dir(project.td.path())
+            .run_and_get_stderr()?;
+
+        let cargo = sysroot.join("bin").join("cargo").display().to_string();
+        let rustc = sysroot.join("bin").join("rustc").display().to_string();
+        assert!(sysroot_was_built(&stderr, TARGET));
+        assert!(stderr.lines().any(|l| {
+            l.contains("-p") && l.contains(&cargo) && l.contains(&format!("RUSTC=\"{}\"", rustc))
+        }));
+
+        Ok(())
+    }
+
+    run!()
+}
+
+/// Check that the default sysroot depends on the properties of the target
+#[test]
+fn default_dependencies() {
+    fn run() -> Result<()> {
+        // a builtin target with atomics and `panic-strategy: abort`
+        const TARGET: &'static str = "thumbv7em-none-eabi";
+
+        let project = Project::new(TARGET)?;
+        xargo()?
+            .args(&["build", "--target", TARGET])
+            .env("XARGO_FORCE_BUILD", "1")
+            .current_dir(project.td.path())
+            .run_and_get_stderr()?;
+        assert!(exists("core", TARGET)?);
+        assert!(exists("compiler_builtins", TARGET)?);
+        assert!(exists("alloc", TARGET)?);
+        assert!(exists("panic_abort", TARGET)?);
+
+        Ok(())
+    }
+
+    run!()
+}
+
+/// Test `xargo build --target foo.json`
+#[test]
+fn target_path() {
+    fn run() -> Result<()> {
+        const TARGET: &'static str = "thumbv6m-target_path-eabi";
+
+        let project = Project::new(TARGET)?;
+        let stderr = project.build_and_get_stderr(Some(&format!("{}.json", TARGET)))?;
+
+        assert!(sysroot_was_built(&stderr, TARGET));
+        assert!(exists("core", TARGET)?);
+
+        Ok(())
+    }
+
+    run!()
+}
+
+/// Test finding the target specification file through `target-path` in
+/// Xargo.toml
+#[test]
+fn target_path_in_xargo_toml() {
+    fn run() -> Result<()> {
+        const TARGET: &'static str = "thumbv6m-target_path_in_xargo_toml-eabi";
+
+        let project = Project::new(TARGET)?;
+        let json = format!("{}.json", TARGET);
+        mkdir(&project.td.path().join("specs"))?;
+        fs::rename(project.td.path().join(&json), project.td.path().join("specs").join(&json))
+            .chain_err(|| format!("couldn't move {}", json))?;
+        project.xargo_toml(
+            r#"
+target-path = "specs"
+"#,
+        )?;
+
+        project.build(TARGET)?;
+        assert!(exists("core", TARGET)?);
+
+        Ok(())
+    }
+
+    run!()
+}
+
+/// Check that an unknown target is reported, with suggestions
+#[test]
+fn unknown_target() {
+    fn run() -> Result<()> {
+        const TARGET: &'static str = "thumbv6m-unknown_target-eabi";
+
+        let project = Project::new(TARGET)?;
+        let out = xargo()?
+            .args(&["build", "--target", "thumbv6m-none-eabu"])
+            .current_dir(project.td.path())
+            .output()
+            .chain_err(|| "couldn't execute `xargo`")?;
+        let stderr = String::from_utf8_lossy(&out.stderr);
+
+        assert!(!out.status.success());
+        assert!(
+            stderr.contains("did you mean") && stderr.contains("thumbv6m-none-eabi"),
+            "unexpected stderr:\n{}", stderr
+        );
+
+        Ok(())
+    }
+
+    run!()
+}
+
+/// Test a `build.target` path, which is relative to the directory containing
+/// the `.cargo` directory that sets it
+#[test]
+fn target_path_config() {
+    fn run() -> Result<()> {
+        const TARGET: &'static str = "thumbv6m-target_path_config-eabi";
+
+        let project = Project::new(TARGET)?;
+        let sub = project.td.path().join("sub");
+        mkdir(&sub)?;
+        mkdir(&sub.join(".cargo"))?;
+        write(
+            &sub.join(".cargo/config"),
+            false,
+            &format!("[build]\ntarget = \"../{}.json\"", TARGET),
+        )?;
+
+        let stderr = xargo()?
+            .args(&["build", "-v"])
+            .current_dir(&sub)
+            .run_and_get_stderr()?;
+
+        assert!(sysroot_was_built(&stderr, TARGET));
+        assert!(exists("core", TARGET)?);
+
+        Ok(())
+    }
+
+    run!()
+}
+
 /// Test building a dependency specified as `target.{}.dependencies` in
 /// ../Xargo.toml
 #[test]
@@ -525,24 +776,33 @@ fn twice() {
     run!()
 }
 
-/// Check that if `build.target` is set in `.cargo/config`, that target will be
-/// used to build the sysroot
+/// Check the status lines, and that `-q` silences them
 #[test]
-fn build_target() {
+fn status() {
     fn run() -> Result<()> {
-        const TARGET: &'static str = "thumbv6m-build_target-eabi";
+        const TARGET: &'static str = "thumbv6m-status-eabi";
 
         let project = Project::new(TARGET)?;
-        project.config(
-            r#"
-[build]
-target = "thumbv6m-build_target-eabi"
-"#,
-        )?;
 
-        let stderr = project.build_and_get_stderr(None)?;
+        let stderr = xargo()?
+            .args(&["build", "--color", "never", "--target", TARGET])
+            .current_dir(project.td.path())
+            .run_and_get_stderr()?;
+        assert!(stderr.contains(&format!("Building sysroot for {}, stage 0 (1/", TARGET)));
+        assert!(stderr.contains("Compiling core ("));
+        assert!(stderr.contains(&format!("Finished sysroot for {}", TARGET)));
 
-        assert!(sysroot_was_built(&stderr, TARGET));
+        let stderr = xargo()?
+            .args(&["build", "--target", TARGET])
+            .current_dir(project.td.path())
+            .run_and_get_stderr()?;
+        assert!(stderr.contains(&format!("Fresh sysroot for {}", TARGET)));
+
+        let stderr = xargo()?
+            .args(&["build", "-q", "--target", TARGET])
+            .current_dir(project.td.path())
+            .run_and_get_stderr()?;
+        assert!(!stderr.contains("sysroot for"));
 
         Ok(())
     }
@@ -550,23 +810,45 @@ target = "thumbv6m-build_target-eabi"
     run!()
 }
 
-/// Check that `--target` overrides `build.target`
+/// Check that adding a later stage doesn't rebuild the earlier stages
 #[test]
-fn override_build_target() {
+fn stage_added() {
     fn run() -> Result<()> {
-        const TARGET: &'static str = "thumbv6m-override_build_target-eabi";
+        const TARGET: &'static str = "thumbv6m-stage_added-eabi";
+        const STAGE2: &'static str = "stage2";
 
-        let project = Project::new(TARGET)?;
-        project.config(
+        let td = TempDir::new("xargo").chain_err(|| "couldn't create a temporary directory")?;
+        let project = Project::new_in(td.path().to_path_buf(), TARGET)?;
+
+        let stderr = project.build_and_get_stderr(Some(TARGET))?;
+
+        assert!(sysroot_was_built(&stderr, TARGET));
+
+        let stage2_path = td.path().join(STAGE2);
+        mkdir(stage2_path.as_path())?;
+        create_simple_project(stage2_path.as_path(), STAGE2, "#![no_std]")?;
+        project.xargo_toml(&format!(
             r#"
-[build]
-target = "BAD"
+[dependencies.core]
+stage = 0
+
+[dependencies.compiler_builtins]
+version = "*"
+features = ["mem"]
+stage = 1
+
+[dependencies.stage2]
+stage = 2
+path = '{}'
 "#,
-        )?;
+            stage2_path.display()
+        ))?;
 
         let stderr = project.build_and_get_stderr(Some(TARGET))?;
 
-        assert!(sysroot_was_built(&stderr, TARGET));
+        assert!(!sysroot_was_built(&stderr, TARGET));
+        assert!(exists("core", TARGET)?);
+        assert!(exists("stage2", TARGET)?);
 
         Ok(())
     }
@@ -574,27 +856,70 @@ target = "BAD"
     run!()
 }
 
-/// We shouldn't rebuild the sysroot if `profile.release.lto` changed
+/// Check that removing the last stage doesn't rebuild the earlier stages, and
+/// that the sysroot is fresh afterwards
 #[test]
-fn lto_changed() {
+fn stage_removed() {
     fn run() -> Result<()> {
-        const TARGET: &'static str = "thumbv6m-lto_changed-eabi";
+        const TARGET: &'static str = "thumbv6m-stage_removed-eabi";
+        const STAGE2: &'static str = "stage2";
+
+        let td = TempDir::new("xargo").chain_err(|| "couldn't create a temporary directory")?;
+        let project = Project::new_in(td.path().to_path_buf(), TARGET)?;
+
+        let stage2_path = td.path().join(STAGE2);
+        mkdir(stage2_path.as_path())?;
+        create_simple_project(stage2_path.as_path(), STAGE2, "#![no_std]")?;
+        project.xargo_toml(&format!(
+            r#"
+[dependencies.core]
+stage = 0
+
+[dependencies.compiler_builtins]
+version = "*"
+features = ["mem"]
+stage = 1
+
+[dependencies.stage2]
+stage = 2
+path = '{}'
+"#,
+            stage2_path.display()
+        ))?;
 
-        let project = Project::new(TARGET)?;
         let stderr = project.build_and_get_stderr(Some(TARGET))?;
 
         assert!(sysroot_was_built(&stderr, TARGET));
+        assert!(exists("stage2", TARGET)?);
 
-        project.cargo_toml(
+        project.xargo_toml(
             r#"
-[profile.release]
-lto = true
+[dependencies.core]
+stage = 0
+
+[dependencies.compiler_builtins]
+version = "*"
+features = ["mem"]
+stage = 1
 "#,
         )?;
 
         let stderr = project.build_and_get_stderr(Some(TARGET))?;
 
         assert!(!sysroot_was_built(&stderr, TARGET));
+        assert!(exists("core", TARGET)?);
+        assert!(!exists("stage2", TARGET)?);
+
+        let stderr = xargo()?
+            .args(&["build", "--target", TARGET])
+            .current_dir(project.td.path())
+            .run_and_get_stderr()?;
+        assert!(stderr.contains(&format!("Fresh sysroot for {}", TARGET)));
+
+        let sbom = home()?.join("lib/rustlib").join(TARGET).join("sbom.cdx.json");
+        let contents = fs::read_to_string(&sbom)
+            .chain_err(|| format!("couldn't read {}", sbom.display()))?;
+        assert!(!contents.contains(STAGE2));
 
         Ok(())
     }
@@ -602,25 +927,22 @@ lto = true
     run!()
 }
 
-/// Modifying RUSTFLAGS should trigger a rebuild of the sysroot
+/// Check that if `build.target` is set in `.cargo/config`, that target will be
+/// used to build the sysroot
 #[test]
-fn rustflags_changed() {
+fn build_target() {
     fn run() -> Result<()> {
-        const TARGET: &'static str = "thumbv6m-rustflags_changed-eabi";
+        const TARGET: &'static str = "thumbv6m-build_target-eabi";
 
         let project = Project::new(TARGET)?;
-        let stderr = project.build_and_get_stderr(Some(TARGET))?;
-
-        assert!(sysroot_was_built(&stderr, TARGET));
-
         project.config(
             r#"
 [build]
-rustflags = ["--cfg", "xargo"]
+target = "thumbv6m-build_target-eabi"
 "#,
         )?;
 
-        let stderr = project.build_and_get_stderr(Some(TARGET))?;
+        let stderr = project.build_and_get_stderr(None)?;
 
         assert!(sysroot_was_built(&stderr, TARGET));
 
@@ -630,27 +952,23 @@ rustflags = ["--cfg", "xargo"]
     run!()
 }
 
-/// Check that RUSTFLAGS are passed to all `rustc`s, and that they can contain spaces.
+/// Check that `--target` overrides `build.target`
 #[test]
-fn rustflags() {
+fn override_build_target() {
     fn run() -> Result<()> {
-        const TARGET: &'static str = "thumbv6m-rustflags-eabi";
+        const TARGET: &'static str = "thumbv6m-override_build_target-eabi";
 
         let project = Project::new(TARGET)?;
-
         project.config(
             r#"
 [build]
-rustflags = ["--cfg", 'xargo="y e s"']
+target = "BAD"
 "#,
         )?;
 
         let stderr = project.build_and_get_stderr(Some(TARGET))?;
 
-        assert!(
-            stderr.contains(r#"'xargo="y e s"'"#),
-            "unexpected stderr:\n{}", stderr
-        );
+        assert!(sysroot_was_built(&stderr, TARGET));
 
         Ok(())
     }
@@ -658,30 +976,21 @@ rustflags = ["--cfg", 'xargo="y e s"']
     run!()
 }
 
-/// Check that `-C panic=abort` is passed to `rustc` when `panic = "abort"` is
-/// set in `profile.release`
+/// Check that global options before the subcommand are not mistaken for it
 #[test]
-fn panic_abort() {
+fn global_options() {
     fn run() -> Result<()> {
-        const TARGET: &'static str = "thumbv6m-panic_abort-eabi";
+        const TARGET: &'static str = "thumbv6m-global_options-eabi";
 
         let project = Project::new(TARGET)?;
 
-        project.cargo_toml(
-            r#"
-[profile.release]
-panic = "abort"
-"#,
-        )?;
-
-        let stderr = project.build_and_get_stderr(Some(TARGET))?;
+        let stderr = xargo()?
+            .args(&["--color", "never", "--config", "build.jobs=1", "build"])
+            .args(&["--target", TARGET, "-v"])
+            .current_dir(project.td.path())
+            .run_and_get_stderr()?;
 
-        assert!(
-            stderr
-                .lines()
-                .filter(|l| !l.starts_with("+") && l.contains("--release"))
-                .all(|l| l.contains("-C") && l.contains("panic=abort"))
-        );
+        assert!(sysroot_was_built(&stderr, TARGET));
 
         Ok(())
     }
@@ -689,28 +998,21 @@ panic = "abort"
     run!()
 }
 
-/// Check that adding linker arguments doesn't trigger a sysroot rebuild
+/// Check that `--locked` also applies to the sysroot build
 #[test]
-fn link_arg() {
+fn locked() {
     fn run() -> Result<()> {
-        const TARGET: &'static str = "thumbv6m-link_arg-eabi";
+        const TARGET: &'static str = "thumbv6m-locked-eabi";
 
         let project = Project::new(TARGET)?;
 
-        let stderr = project.build_and_get_stderr(Some(TARGET))?;
+        let stderr = xargo()?
+            .args(&["build", "--locked", "--target", TARGET, "-v"])
+            .current_dir(project.td.path())
+            .run_and_get_stderr()?;
 
         assert!(sysroot_was_built(&stderr, TARGET));
-
-        project.config(
-            r#"
-[target.__link_arg]
-rustflags = ["-C", "link-arg=-lfoo"]
-"#,
-        )?;
-
-        let stderr = project.build_and_get_stderr(Some(TARGET))?;
-
-        assert!(!sysroot_was_built(&stderr, TARGET));
+        assert!(stderr.contains("metadata --format-version 1"));
 
         Ok(())
     }
@@ -718,13 +1020,834 @@ rustflags = ["-C", "link-arg=-lfoo"]
     run!()
 }
 
-/// The sysroot should be rebuilt if the target specification changed
+/// Check that only some `.cargo/config` sections can be used by the sysroot
+/// build
 #[test]
-fn specification_changed() {
+fn cargo_config_sections() {
     fn run() -> Result<()> {
-        const JSON: &'static str = r#"
-{
-    "arch": "arm",
+        const TARGET: &'static str = "thumbv6m-cargo_config_sections-eabi";
+
+        let project = Project::new(TARGET)?;
+        project.xargo_toml(
+            r#"
+cargo-config = ["source", "build"]
+"#,
+        )?;
+
+        let out = xargo()?
+            .args(&["build", "--target", TARGET])
+            .current_dir(project.td.path())
+            .output()
+            .chain_err(|| "couldn't execute `xargo`")?;
+        let stderr = String::from_utf8_lossy(&out.stderr);
+
+        assert!(!out.status.success());
+        assert!(
+            stderr.contains("`cargo-config` can't include `build`"),
+            "unexpected stderr:\n{}", stderr
+        );
+
+        Ok(())
+    }
+
+    run!()
+}
+
+/// Check that an allowed `.cargo/config` section applies to the sysroot build
+#[test]
+fn cargo_config_source() {
+    fn run() -> Result<()> {
+        const TARGET: &'static str = "thumbv6m-cargo_config_source-eabi";
+
+        let project = Project::new(TARGET)?;
+        vendor(&project.td.path().join("vendor"))?;
+        // The relative path has to keep working from the stage directory
+        project.config(
+            r#"
+[source.crates-io]
+replace-with = "vendored"
+
+[source.vendored]
+directory = "vendor"
+"#,
+        )?;
+        project.xargo_toml(
+            r#"
+[dependencies.core]
+stage = 0
+
+[dependencies.xargo-vendored]
+version = "0.1.0"
+stage = 1
+"#,
+        )?;
+
+        // `xargo-vendored` isn't on crates.io, so this only works if the
+        // source replacement applies
+        project.build(TARGET)?;
+        assert!(exists("xargo_vendored", TARGET)?);
+
+        Ok(())
+    }
+
+    run!()
+}
+
+/// Check that registry tokens aren't copied into the configuration of the
+/// sysroot build
+#[test]
+fn cargo_config_credentials() {
+    fn run() -> Result<()> {
+        const TARGET: &'static str = "thumbv6m-cargo_config_credentials-eabi";
+
+        let project = Project::new(TARGET)?;
+        project.config(
+            r#"
+[registry]
+token = "xargo-registry-token"
+
+[registries.xargo]
+index = "https://example.com/xargo-index"
+token = "xargo-registries-token"
+"#,
+        )?;
+
+        // Keep the stage directories around to look at their configuration
+        let stderr = xargo()?
+            .args(&["build", "--target", TARGET, "-v"])
+            .env("XARGO_KEEP_TEMP", "1")
+            .current_dir(project.td.path())
+            .run_and_get_stderr()?;
+
+        let dirs = stderr
+            .lines()
+            .filter(|l| l.starts_with("+ cd \""))
+            .filter_map(|l| l["+ cd \"".len()..].split('"').next())
+            .collect::<BTreeSet<_>>();
+        assert!(!dirs.is_empty(), "unexpected stderr:\n{}", stderr);
+
+        for dir in dirs {
+            let config = Path::new(dir).join(".cargo").join("config.toml");
+            let contents = fs::read_to_string(&config)
+                .chain_err(|| format!("couldn't read {}", config.display()))?;
+            fs::remove_dir_all(dir).ok();
+
+            assert!(contents.contains("xargo-index"));
+            assert!(!contents.contains("token"), "unexpected config:\n{}", contents);
+        }
+
+        Ok(())
+    }
+
+    run!()
+}
+
+/// Check that the lockfile of the sysroot is kept, and that it can be used to
+/// pin the sysroot dependencies
+#[test]
+fn lockfile() {
+    fn run() -> Result<()> {
+        const TARGET: &'static str = "thumbv6m-lockfile-eabi";
+
+        const XARGO_TOML: &'static str = r#"
+[dependencies.core]
+stage = 0
+
+[dependencies.compiler_builtins]
+version = "*"
+features = ["mem"]
+stage = 1
+"#;
+
+        let project = Project::new(TARGET)?;
+        project.xargo_toml(XARGO_TOML)?;
+        project.build(TARGET)?;
+
+        let read = |name: &str| -> Result<String> {
+            let lockfile = home()?.join("lib/rustlib").join(TARGET).join(name);
+            fs::read_to_string(&lockfile)
+                .chain_err(|| format!("couldn't read {}", lockfile.display()))
+        };
+
+        // Each stage only has its own crates, the merged lockfile has all of
+        // them
+        let stage0 = read("Cargo.lock.stage-0")?;
+        let stage1 = read("Cargo.lock.stage-1")?;
+        let contents = read("Cargo.lock")?;
+        assert!(stage0.contains("name = \"core\""));
+        assert!(!stage0.contains("name = \"compiler_builtins\""));
+        assert!(stage1.contains("name = \"compiler_builtins\""));
+        assert!(contents.contains("name = \"core\""));
+        assert!(contents.contains("name = \"compiler_builtins\""));
+
+        write(&project.td.path().join("sysroot.lock"), false, &contents)?;
+        project.xargo_toml(&format!("lockfile = \"sysroot.lock\"\n{}", XARGO_TOML))?;
+
+        let stderr = xargo()?
+            .args(&["build", "--locked", "--target", TARGET, "-v"])
+            .current_dir(project.td.path())
+            .run_and_get_stderr()?;
+
+        assert!(sysroot_was_built(&stderr, TARGET));
+        assert!(stderr.contains("sysroot.lock"));
+
+        Ok(())
+    }
+
+    run!()
+}
+
+/// Check that a lockfile is generated when the Rust source has none
+#[test]
+fn lockfile_generated() {
+    fn run() -> Result<()> {
+        const TARGET: &'static str = "thumbv6m-lockfile_generated-eabi";
+
+        let project = Project::new(TARGET)?;
+
+        // A copy of `rust-src` without its `Cargo.lock`
+        let src = TempDir::new("xargo").chain_err(|| "couldn't create a temporary directory")?;
+        let rust_src = rust_src()?;
+        cp_r(&rust_src.join("library"), &src.path().join("library"))?;
+        for f in &["git-commit-info", "git-commit-hash", "version"] {
+            if rust_src.join(f).is_file() {
+                fs::copy(rust_src.join(f), src.path().join(f))
+                    .chain_err(|| format!("couldn't copy {}", f))?;
+            }
+        }
+
+        let stderr = xargo()?
+            .args(&["build", "--target", TARGET, "-v"])
+            .env("XARGO_RUST_SRC", src.path().join("library"))
+            .current_dir(project.td.path())
+            .run_and_get_stderr()?;
+
+        assert!(stderr.contains("has no Cargo.lock; generating one offline"));
+        assert!(stderr.contains("generate-lockfile"));
+        assert!(sysroot_was_built(&stderr, TARGET));
+        assert!(exists("core", TARGET)?);
+
+        Ok(())
+    }
+
+    run!()
+}
+
+/// Check that a `lockfile` in `Xargo.toml` that doesn't exist is reported
+#[test]
+fn lockfile_missing() {
+    fn run() -> Result<()> {
+        const TARGET: &'static str = "thumbv6m-lockfile_missing-eabi";
+
+        let project = Project::new(TARGET)?;
+        project.xargo_toml("lockfile = \"missing.lock\"")?;
+
+        let out = xargo()?
+            .args(&["build", "--target", TARGET])
+            .current_dir(project.td.path())
+            .output()
+            .chain_err(|| "couldn't execute `xargo`")?;
+        let stderr = String::from_utf8_lossy(&out.stderr);
+
+        assert!(!out.status.success());
+        assert!(
+            stderr.contains("Xargo.toml: `lockfile`") && stderr.contains("missing.lock"),
+            "unexpected stderr:\n{}", stderr
+        );
+
+        Ok(())
+    }
+
+    run!()
+}
+
+/// Check that an SBOM is written next to the sysroot
+#[test]
+fn sbom() {
+    fn run() -> Result<()> {
+        const TARGET: &'static str = "thumbv6m-sbom-eabi";
+
+        let project = Project::new(TARGET)?;
+        project.build(TARGET)?;
+
+        let sbom = home()?.join("lib/rustlib").join(TARGET).join("sbom.cdx.json");
+        let contents = fs::read_to_string(&sbom)
+            .chain_err(|| format!("couldn't read {}", sbom.display()))?;
+        assert!(contents.contains("\"bomFormat\": \"CycloneDX\""));
+        assert!(contents.contains("\"name\": \"core\""));
+
+        Ok(())
+    }
+
+    run!()
+}
+
+/// Check that `--timings` reports how long building the sysroot took
+#[test]
+fn timings() {
+    fn run() -> Result<()> {
+        const TARGET: &'static str = "thumbv6m-timings-eabi";
+
+        let project = Project::new(TARGET)?;
+
+        let stderr = xargo()?
+            .args(&["build", "--timings", "--target", TARGET])
+            .current_dir(project.td.path())
+            .run_and_get_stderr()?;
+        assert!(stderr.contains(&format!("Timings sysroot for {}", TARGET)));
+        assert!(stderr.contains("stage 0:"));
+
+        let timings = home()?.join("lib/rustlib").join(TARGET).join("timings.json");
+        let contents = fs::read_to_string(&timings)
+            .chain_err(|| format!("couldn't read {}", timings.display()))?;
+        assert!(contents.contains(&format!("\"target\": \"{}\"", TARGET)));
+        assert!(contents.contains("\"name\": \"core\""));
+
+        Ok(())
+    }
+
+    run!()
+}
+
+/// Check that the sysroot can be built from a vendor directory
+#[test]
+fn offline_vendor() {
+    fn run() -> Result<()> {
+        const TARGET: &'static str = "thumbv6m-offline_vendor-eabi";
+
+        let project = Project::new(TARGET)?;
+
+        vendor(&project.td.path().join("vendor"))?;
+        project.xargo_toml(
+            r#"
+[offline]
+vendor = "vendor"
+
+[dependencies.core]
+stage = 0
+
+[dependencies.xargo-vendored]
+version = "0.1.0"
+stage = 1
+"#,
+        )?;
+
+        let stderr = project.build_and_get_stderr(Some(TARGET))?;
+
+        assert!(stderr.contains("--offline"));
+        assert!(stderr.contains("source.xargo-offline.directory"));
+        assert!(exists("xargo_vendored", TARGET)?);
+
+        Ok(())
+    }
+
+    run!()
+}
+
+/// Check that an offline build lists the crates it's missing up front
+#[test]
+fn offline_missing() {
+    fn run() -> Result<()> {
+        const TARGET: &'static str = "thumbv6m-offline_missing-eabi";
+
+        let project = Project::new(TARGET)?;
+        mkdir(&project.td.path().join("vendor"))?;
+        project.xargo_toml(
+            r#"
+[offline]
+vendor = "vendor"
+
+[dependencies.core]
+stage = 0
+
+[dependencies.xargo-no-such-crate]
+version = "1.0.0"
+stage = 1
+"#,
+        )?;
+
+        let out = xargo()?
+            .args(&["build", "--target", TARGET])
+            .current_dir(project.td.path())
+            .output()
+            .chain_err(|| "couldn't execute `xargo`")?;
+        let stderr = String::from_utf8_lossy(&out.stderr);
+
+        assert!(!out.status.success());
+        assert!(
+            stderr.contains("the sysroot can't be built offline")
+                && stderr.contains("the vendor directory")
+                && stderr.contains("xargo-no-such-crate 1.0.0"),
+            "unexpected stderr:\n{}", stderr
+        );
+
+        Ok(())
+    }
+
+    run!()
+}
+
+/// Check that a failing sysroot build comes with a hint about its cause
+#[test]
+fn diagnostics() {
+    fn run() -> Result<()> {
+        const TARGET: &'static str = "thumbv6m-diagnostics-eabi";
+
+        let project = Project::new(TARGET)?;
+        project.xargo_toml(
+            r#"
+[dependencies.core]
+
+[dependencies.xargo-no-such-crate]
+git = "https://example.invalid/xargo-no-such-crate"
+"#,
+        )?;
+
+        // Git dependencies can't be fetched offline
+        let out = xargo()?
+            .args(&["build", "--target", TARGET])
+            .env("XARGO_OFFLINE", "1")
+            .current_dir(project.td.path())
+            .output()
+            .chain_err(|| "couldn't execute `xargo`")?;
+        let stderr = String::from_utf8_lossy(&out.stderr);
+
+        assert!(!out.status.success());
+        assert!(
+            stderr.contains("help: Cargo couldn't resolve the dependencies of the sysroot"),
+            "unexpected stderr:\n{}", stderr
+        );
+
+        Ok(())
+    }
+
+    run!()
+}
+
+/// Runs `xargo build` for `target` in `dir`, which must fail, and returns its
+/// stderr
+fn build_and_fail(dir: &Path, target: &str, env: &[(&str, &Path)]) -> Result<String> {
+    let mut cmd = xargo()?;
+    cmd.args(&["build", "--target", target]).current_dir(dir);
+    for &(k, v) in env {
+        cmd.env(k, v);
+    }
+
+    let out = cmd.output().chain_err(|| "couldn't execute `xargo`")?;
+    assert!(!out.status.success());
+
+    Ok(String::from_utf8_lossy(&out.stderr).into_owned())
+}
+
+/// Check the hint for a Rust source that lacks some crates
+#[test]
+fn diagnostics_incomplete_src() {
+    fn run() -> Result<()> {
+        const TARGET: &'static str = "thumbv6m-diagnostics_incomplete_src-eabi";
+
+        let project = Project::new(TARGET)?;
+        project.xargo_toml("[dependencies.core]")?;
+
+        // No `core` in there
+        let src = TempDir::new("xargo").chain_err(|| "couldn't create a temporary directory")?;
+        mkdir(&src.path().join("library"))?;
+
+        let stderr = build_and_fail(
+            project.td.path(),
+            TARGET,
+            &[("XARGO_RUST_SRC", &src.path().join("library"))],
+        )?;
+        assert!(
+            stderr.contains("help: the Rust source looks incomplete"),
+            "unexpected stderr:\n{}", stderr
+        );
+
+        Ok(())
+    }
+
+    run!()
+}
+
+/// Check the hint for a Rust source that's newer than `rustc`
+#[test]
+fn diagnostics_version_skew() {
+    fn run() -> Result<()> {
+        const TARGET: &'static str = "thumbv6m-diagnostics_version_skew-eabi";
+
+        let project = Project::new(TARGET)?;
+        project.xargo_toml("[dependencies.core]")?;
+
+        // A `core` that needs a feature this `rustc` doesn't know about
+        let src = TempDir::new("xargo").chain_err(|| "couldn't create a temporary directory")?;
+        let core = src.path().join("library/core");
+        mkdir(&src.path().join("library"))?;
+        mkdir(&core)?;
+        mkdir(&core.join("src"))?;
+        write(
+            &core.join("Cargo.toml"),
+            false,
+            "[package]\nname = \"core\"\nversion = \"0.0.0\"\n\n[lib]\npath = \"src/lib.rs\"\n",
+        )?;
+        write(
+            &core.join("src/lib.rs"),
+            false,
+            "#![feature(no_core, xargo_from_the_future)]\n#![no_core]\n",
+        )?;
+
+        let stderr = build_and_fail(
+            project.td.path(),
+            TARGET,
+            &[("XARGO_RUST_SRC", &src.path().join("library"))],
+        )?;
+        assert!(
+            stderr.contains("help: the Rust source doesn't seem to match `rustc`"),
+            "unexpected stderr:\n{}", stderr
+        );
+
+        Ok(())
+    }
+
+    run!()
+}
+
+/// Check the hint for a crate of a later stage that builds `core` again
+#[test]
+fn diagnostics_duplicate_lang_item() {
+    fn run() -> Result<()> {
+        const TARGET: &'static str = "thumbv7m-none-eabi";
+        const STAGE1: &'static str = "dup";
+
+        let td = TempDir::new("xargo").chain_err(|| "couldn't create a temporary directory")?;
+        let project = Project::new_in(td.path().to_path_buf(), TARGET)?;
+
+        // `dup` gets `core` both from the sysroot, through `alloc`, and from
+        // its own dependency
+        let stage1 = td.path().join(STAGE1);
+        mkdir(&stage1)?;
+        create_simple_project(&stage1, STAGE1, "#![no_std]\nextern crate alloc;")?;
+        write(
+            &stage1.join("Cargo.toml"),
+            true,
+            &format!("core = {{ path = '{}' }}\n", rust_src()?.join("library/core").display()),
+        )?;
+        project.xargo_toml(&format!(
+            r#"
+[dependencies.alloc]
+stage = 0
+
+[dependencies.dup]
+stage = 1
+path = '{}'
+"#,
+            stage1.display()
+        ))?;
+
+        let stderr = build_and_fail(project.td.path(), TARGET, &[])?;
+        assert!(
+            stderr.contains("help: a standard crate was built more than once"),
+            "unexpected stderr:\n{}", stderr
+        );
+
+        Ok(())
+    }
+
+    run!()
+}
+
+/// Check the hint for a sysroot without `memcpy` and friends, which only
+/// shows when the project fails to build
+#[test]
+fn diagnostics_mem() {
+    fn run() -> Result<()> {
+        const TARGET: &'static str = "thumbv6m-diagnostics_mem-eabi";
+        const JSON: &'static str = r#"
+{
+    "arch": "arm",
+    "data-layout": "e-m:e-p:32:32-i64:64-v128:64:128-a:0:32-n32-S64",
+    "linker": "rust-lld",
+    "linker-flavor": "ld.lld",
+    "llvm-target": "thumbv6m-none-eabi",
+    "max-atomic-width": 0,
+    "os": "none",
+    "panic-strategy": "abort",
+    "target-c-int-width": "32",
+    "target-endian": "little",
+    "target-pointer-width": "32"
+}
+"#;
+
+        let td = TempDir::new("xargo").chain_err(|| "couldn't create a temporary directory")?;
+        xargo()?
+            .args(&["init", "-q", "--bin", "--vcs", "none", "--name", "mem"])
+            .current_dir(td.path())
+            .run()?;
+        write(&td.path().join(format!("{}.json", TARGET)), false, JSON)?;
+        write(
+            &td.path().join("src/main.rs"),
+            false,
+            r#"#![no_std]
+#![no_main]
+
+static mut SRC: [u8; 64] = [0; 64];
+static mut DST: [u8; 64] = [0; 64];
+
+#[no_mangle]
+pub unsafe extern "C" fn _start() -> ! {
+    let n = core::ptr::read_volatile(&64usize);
+    core::ptr::copy_nonoverlapping(
+        core::ptr::addr_of!(SRC) as *const u8,
+        core::ptr::addr_of_mut!(DST) as *mut u8,
+        n,
+    );
+    loop {}
+}
+
+#[panic_handler]
+fn panic(_: &core::panic::PanicInfo) -> ! {
+    loop {}
+}
+"#,
+        )?;
+        // `compiler_builtins` without the `mem` feature
+        write(
+            &td.path().join("Xargo.toml"),
+            false,
+            "[dependencies.core]\n\n[dependencies.compiler_builtins]\n",
+        )?;
+
+        let stderr = build_and_fail(td.path(), TARGET, &[]);
+        cleanup(TARGET)?;
+        let stderr = stderr?;
+
+        assert!(
+            stderr.contains("help: nothing in the sysroot provides `memcpy` and friends"),
+            "unexpected stderr:\n{}", stderr
+        );
+
+        Ok(())
+    }
+
+    run!()
+}
+
+/// Check that the sysroot build's JSON messages can be told apart from the
+/// project's
+#[test]
+fn message_format_json() {
+    fn run() -> Result<()> {
+        const TARGET: &'static str = "thumbv6m-message_format_json-eabi";
+
+        let project = Project::new(TARGET)?;
+
+        let out = xargo()?
+            .args(&["build", "--message-format", "json", "--target", TARGET])
+            .current_dir(project.td.path())
+            .output()
+            .chain_err(|| "couldn't execute `xargo`")?;
+        assert!(out.status.success());
+        let stdout = String::from_utf8_lossy(&out.stdout);
+
+        assert!(stdout.contains("\"reason\":\"xargo-sysroot-build-started\""));
+        assert!(stdout.contains("\"reason\":\"xargo-sysroot-stage-finished\""));
+        assert!(stdout
+            .lines()
+            .filter(|l| l.starts_with("{\"reason\":\"compiler-artifact\""))
+            .all(|l| !l.contains("\"name\":\"core\"")));
+
+        let out = xargo()?
+            .args(&["build", "--message-format", "json", "--target", TARGET])
+            .current_dir(project.td.path())
+            .output()
+            .chain_err(|| "couldn't execute `xargo`")?;
+        assert!(out.status.success());
+        let stdout = String::from_utf8_lossy(&out.stdout);
+
+        assert!(stdout.contains("\"reason\":\"xargo-sysroot-fresh\""));
+
+        Ok(())
+    }
+
+    run!()
+}
+
+/// Check that `--target` is picked up from a Cargo alias
+#[test]
+fn alias() {
+    fn run() -> Result<()> {
+        const TARGET: &'static str = "thumbv6m-alias-eabi";
+
+        let project = Project::new(TARGET)?;
+        project.config(
+            r#"
+[alias]
+xb = "build --target thumbv6m-alias-eabi -v"
+"#,
+        )?;
+
+        let stderr = xargo()?
+            .arg("xb")
+            .current_dir(project.td.path())
+            .run_and_get_stderr()?;
+
+        assert!(sysroot_was_built(&stderr, TARGET));
+
+        Ok(())
+    }
+
+    run!()
+}
+
+/// We shouldn't rebuild the sysroot if `profile.release.lto` changed
+#[test]
+fn lto_changed() {
+    fn run() -> Result<()> {
+        const TARGET: &'static str = "thumbv6m-lto_changed-eabi";
+
+        let project = Project::new(TARGET)?;
+        let stderr = project.build_and_get_stderr(Some(TARGET))?;
+
+        assert!(sysroot_was_built(&stderr, TARGET));
+
+        project.cargo_toml(
+            r#"
+[profile.release]
+lto = true
+"#,
+        )?;
+
+        let stderr = project.build_and_get_stderr(Some(TARGET))?;
+
+        assert!(!sysroot_was_built(&stderr, TARGET));
+
+        Ok(())
+    }
+
+    run!()
+}
+
+/// Modifying RUSTFLAGS should trigger a rebuild of the sysroot
+#[test]
+fn rustflags_changed() {
+    fn run() -> Result<()> {
+        const TARGET: &'static str = "thumbv6m-rustflags_changed-eabi";
+
+        let project = Project::new(TARGET)?;
+        let stderr = project.build_and_get_stderr(Some(TARGET))?;
+
+        assert!(sysroot_was_built(&stderr, TARGET));
+
+        project.config(
+            r#"
+[build]
+rustflags = ["--cfg", "xargo"]
+"#,
+        )?;
+
+        let stderr = project.build_and_get_stderr(Some(TARGET))?;
+
+        assert!(sysroot_was_built(&stderr, TARGET));
+
+        Ok(())
+    }
+
+    run!()
+}
+
+/// Check that RUSTFLAGS are passed to all `rustc`s, and that they can contain spaces.
+#[test]
+fn rustflags() {
+    fn run() -> Result<()> {
+        const TARGET: &'static str = "thumbv6m-rustflags-eabi";
+
+        let project = Project::new(TARGET)?;
+
+        project.config(
+            r#"
+[build]
+rustflags = ["--cfg", 'xargo="y e s"']
+"#,
+        )?;
+
+        let stderr = project.build_and_get_stderr(Some(TARGET))?;
+
+        assert!(
+            stderr.contains(r#"'xargo="y e s"'"#),
+            "unexpected stderr:\n{}", stderr
+        );
+
+        Ok(())
+    }
+
+    run!()
+}
+
+/// Check that `-C panic=abort` is passed to `rustc` when `panic = "abort"` is
+/// set in `profile.release`
+#[test]
+fn panic_abort() {
+    fn run() -> Result<()> {
+        const TARGET: &'static str = "thumbv6m-panic_abort-eabi";
+
+        let project = Project::new(TARGET)?;
+
+        project.cargo_toml(
+            r#"
+[profile.release]
+panic = "abort"
+"#,
+        )?;
+
+        let stderr = project.build_and_get_stderr(Some(TARGET))?;
+
+        assert!(
+            stderr
+                .lines()
+                .filter(|l| !l.starts_with("+") && l.contains("--release"))
+                .all(|l| l.contains("-C") && l.contains("panic=abort"))
+        );
+
+        Ok(())
+    }
+
+    run!()
+}
+
+/// Check that adding linker arguments doesn't trigger a sysroot rebuild
+#[test]
+fn link_arg() {
+    fn run() -> Result<()> {
+        const TARGET: &'static str = "thumbv6m-link_arg-eabi";
+
+        let project = Project::new(TARGET)?;
+
+        let stderr = project.build_and_get_stderr(Some(TARGET))?;
+
+        assert!(sysroot_was_built(&stderr, TARGET));
+
+        project.config(
+            r#"
+[target.__link_arg]
+rustflags = ["-C", "link-arg=-lfoo"]
+"#,
+        )?;
+
+        let stderr = project.build_and_get_stderr(Some(TARGET))?;
+
+        assert!(!sysroot_was_built(&stderr, TARGET));
+
+        Ok(())
+    }
+
+    run!()
+}
+
+/// The sysroot should be rebuilt if the target specification changed
+#[test]
+fn specification_changed() {
+    fn run() -> Result<()> {
+        const JSON: &'static str = r#"
+{
+    "arch": "arm",
     "data-layout": "e-m:e-p:32:32-i64:64-v128:64:128-a:0:32-n32-S64",
     "linker-flavor": "gcc",
     "llvm-target": "thumbv6m-none-eabi",
@@ -802,6 +1925,135 @@ fn unchanged_specification() {
     run!()
 }
 
+/// The sysroot should NOT be rebuilt if a default value of the target
+/// specification was made explicit
+#[test]
+fn explicit_default_specification() {
+    fn run() -> Result<()> {
+        const JSON: &'static str = r#"
+{
+    "arch": "arm",
+    "data-layout": "e-m:e-p:32:32-i64:64-v128:64:128-a:0:32-n32-S64",
+    "linker-flavor": "gcc",
+    "llvm-target": "thumbv6m-none-eabi",
+    "max-atomic-width": 0,
+    "os": "none",
+    "panic-strategy": "unwind",
+    "target-c-int-width": "32",
+    "target-endian": "little",
+    "target-pointer-width": "32"
+}
+"#;
+        const TARGET: &'static str = "thumbv6m-explicit_default_specification-eabi";
+
+        let project = Project::new(TARGET)?;
+
+        let stderr = project.build_and_get_stderr(Some(TARGET))?;
+
+        assert!(sysroot_was_built(&stderr, TARGET));
+
+        write(
+            &project.td.path().join("thumbv6m-explicit_default_specification-eabi.json"),
+            false,
+            JSON,
+        )?;
+
+        let stderr = project.build_and_get_stderr(Some(TARGET))?;
+
+        assert!(!sysroot_was_built(&stderr, TARGET));
+
+        Ok(())
+    }
+
+    run!()
+}
+
+/// An invalid target specification should be reported before building the
+/// sysroot
+#[test]
+fn invalid_specification() {
+    fn run() -> Result<()> {
+        const TARGET: &'static str = "thumbv6m-invalid_specification-eabi";
+
+        let project = Project::new(TARGET)?;
+        write(
+            &project.td.path().join("thumbv6m-invalid_specification-eabi.json"),
+            false,
+            r#"{ "arch": "arm" }"#,
+        )?;
+
+        let out = xargo()?
+            .args(&["build", "--target", TARGET, "-v"])
+            .current_dir(project.td.path())
+            .output()
+            .chain_err(|| "couldn't execute `xargo`")?;
+        let stderr = String::from_utf8_lossy(&out.stderr);
+
+        assert!(!out.status.success());
+        assert!(!sysroot_was_built(&stderr, TARGET));
+        assert!(
+            stderr.contains("is not a valid target specification file"),
+            "unexpected stderr:\n{}", stderr
+        );
+
+        Ok(())
+    }
+
+    run!()
+}
+
+/// Check that no sysroot is built for the host when the prebuilt one will do
+#[test]
+fn host_prebuilt() {
+    fn run() -> Result<()> {
+        let target = host();
+        let project = HProject::new(false)?;
+
+        let stderr = xargo()?
+            .args(&["build", "-v"])
+            .current_dir(project.td.path())
+            .run_and_get_stderr()?;
+
+        assert!(!sysroot_was_built(&stderr, &target));
+        assert!(stderr.contains("using the prebuilt sysroot"));
+
+        Ok(())
+    }
+
+    run!()
+}
+
+/// Check that Rust source from another commit than `rustc` is rejected
+#[test]
+fn src_version_mismatch() {
+    fn run() -> Result<()> {
+        let project = HProject::new(false)?;
+
+        let src = TempDir::new("xargo").chain_err(|| "couldn't create a temporary directory")?;
+        mkdir(&src.path().join("library"))?;
+        write(&src.path().join("git-commit-hash"), false, "0000000000000000000000000000000000000000")?;
+
+        let out = xargo()?
+            .arg("build")
+            .env("XARGO_FORCE_BUILD", "1")
+            .env("XARGO_RUST_SRC", src.path().join("library"))
+            .current_dir(project.td.path())
+            .output()
+            .chain_err(|| "couldn't execute `xargo`")?;
+        let stderr = String::from_utf8_lossy(&out.stderr);
+
+        assert!(!out.status.success());
+        assert!(
+            stderr.contains("is from commit 0000000000000000000000000000000000000000"),
+            "unexpected stderr:\n{}", stderr
+        );
+
+        Ok(())
+    }
+
+    run!()
+}
+
 /// Check that a sysroot is built for the host
 #[test]
 fn host_once() {
@@ -841,6 +2093,26 @@ fn host_twice() {
     run!()
 }
 
+/// Check that commands that don't compile anything don't build a sysroot
+#[test]
+fn host_metadata() {
+    fn run() -> Result<()> {
+        let target = host();
+        let project = HProject::new(false)?;
+
+        let stderr = xargo()?
+            .args(&["metadata", "--format-version", "1", "-v"])
+            .current_dir(project.td.path())
+            .run_and_get_stderr()?;
+
+        assert!(!sysroot_was_built(&stderr, &target));
+
+        Ok(())
+    }
+
+    run!()
+}
+
 /// Check we can build+run `xargo test`
 #[test]
 fn host_libtest() {
@@ -954,3 +2226,20 @@ fn cargo_check_check_no_ctoml() {
     }
     run!()
 }
+
+/// Check that `xargo` doesn't reuse a sysroot that was built by `xargo-check`
+#[test]
+fn cargo_check_then_build() {
+    fn run() -> Result<()> {
+        let target = host();
+        let project = HProject::new(false)?;
+        project.xargo_check_subcommand(None, None)?;
+
+        let stderr = project.build_and_get_stderr()?;
+
+        assert!(sysroot_was_built(&stderr, &target));
+
+        Ok(())
+    }
+    run!()
+}